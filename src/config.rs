@@ -1,47 +1,150 @@
 use std::borrow::ToOwned;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{self, Formatter, Show};
 use std::io::{File, IoError, IoErrorKind, IoResult};
-use std::io::fs::PathExtensions;
+use std::io::fs::{self, PathExtensions};
+use std::os;
 
-use toml;
+use cron::Cron;
+use user::Credentials;
+use util::is_executable;
+
+use regex::Regex;
+use rustc_serialize::Decodable;
+use toml::{self, Value};
 
 pub static DEFAULT_SOCKET_PATH: &'static str = "/tmp/shepherd.sock";
-static CONFIG_PATHS: &'static [&'static str] = &["shepherd.toml"];
+/// Where root's logs go unless `log_dir` is set, see `default_log_dir()`
+pub static SYSTEM_LOG_DIR: &'static str = "/var/log/shepherd";
+static DAEMON_LOG_FILE: &'static str = "shepherd-daemon.log";
+static CONFIG_FILE: &'static str = "shepherd.toml";
+/// Names a config file to use instead of searching for one
+pub static CONFIG_ENV_VAR: &'static str = "SHEPHERD_CONFIG";
+
+/// Separates a server's name from an instance number, e.g. `web@2`
+pub const INSTANCE_SEPARATOR: char = '@';
+
+/// Find the config file, made absolute so the daemon uses the same one wherever it's started from.
+///
+/// `explicit` (from `--config`) or else `$SHEPHERD_CONFIG` must exist if given. Otherwise the first `shepherd.toml`
+/// found in the current directory, `$XDG_CONFIG_HOME/shepherd/` (`~/.config/shepherd/` by default)
+/// and `/etc/shepherd/` is used.
+pub fn find_config(explicit: Option<&str>) -> IoResult<Path> {
+    let given = explicit.map(|path| path.to_owned()).or_else(|| os::getenv(CONFIG_ENV_VAR));
 
-fn find_config() -> IoResult<Path> {
-    for path in CONFIG_PATHS.iter() {
+    if let Some(path) = given {
         let path = Path::new(path);
-        
+
+        return if path.exists() {
+            os::make_absolute(&path)
+        } else {
+            Err(
+                IoError {
+                    kind: IoErrorKind::PathDoesntExist,
+                    desc: "Config file not found.",
+                    detail: Some(format!("{} does not exist", path.display())),
+                }
+            )
+        };
+    }
+
+    let paths = search_paths();
+
+    for path in paths.iter() {
         if path.exists() {
-            return Ok(path);    
+            return os::make_absolute(path);    
         }
     }
 
+    let searched: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+
     Err(
         IoError {
             kind: IoErrorKind::PathDoesntExist,
             desc: "Config file not found.",
-            detail: Some(format!("Searched paths: {:?}", CONFIG_PATHS))
+            detail: Some(format!("Searched paths: {:?}", searched))
         }
     )
 }
 
+fn search_paths() -> Vec<Path> {
+    let mut paths = vec![Path::new(CONFIG_FILE)];
+
+    // Relative values are to be ignored, per the XDG spec
+    let config_home = os::getenv("XDG_CONFIG_HOME").map(|dir| Path::new(dir))
+        .and_then(|dir| if dir.is_absolute() { Some(dir) } else { None })
+        .or_else(|| os::homedir().map(|home| home.join(".config")));
+
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join("shepherd").join(CONFIG_FILE));
+    }
+
+    paths.push(Path::new("/etc/shepherd").join(CONFIG_FILE));
+    paths
+}
+
+/// `/var/log/shepherd` for root, otherwise `$XDG_STATE_HOME/shepherd` (`~/.local/state/shepherd` by default).
+/// Either belongs to the daemon's user, so nobody else can create it first or plant symlinks in it.
+fn default_log_dir() -> String {
+    if unsafe { ::libc::geteuid() } == 0 {
+        return SYSTEM_LOG_DIR.to_owned();
+    }
+
+    // Relative values are to be ignored, per the XDG spec
+    let state_home = os::getenv("XDG_STATE_HOME").map(|dir| Path::new(dir))
+        .and_then(|dir| if dir.is_absolute() { Some(dir) } else { None })
+        .or_else(|| os::homedir().map(|home| home.join(".local").join("state")));
+
+    match state_home {
+        Some(state_home) => state_home.join("shepherd").display().to_string(),
+        None => SYSTEM_LOG_DIR.to_owned(),
+    }
+}
+
+/// Everything but `servers`, which are decoded one at a time so errors can say where they are.
 #[derive(RustcDecodable)]
 struct TomlDecode {
     shepherd: Shepherd,
-    servers: HashMap<String, ServerConfig>,          
 }
 
 impl TomlDecode {
-    fn into_config(self) -> Config {
+    /// `without_logs` are the servers with `log_file = false`.
+    fn into_config(self, path: Path, mut servers: HashMap<String, ServerConfig>, without_logs: HashSet<String>,
+                   unknown_keys: Vec<String>) -> Config {
         let socket_path = self.shepherd.socket_path
             .unwrap_or_else(|| DEFAULT_SOCKET_PATH.to_owned());
+
+        let log_dir = self.shepherd.log_dir
+            .unwrap_or_else(default_log_dir);
+
+        let pid_file = self.shepherd.pid_file
+            .unwrap_or_else(|| format!("{}.pid", socket_path));
+
+        // Relative log paths are resolved against the log dir, absolute ones are kept as-is
+        for (name, server) in servers.iter_mut() {
+            if without_logs.contains(name) {
+                continue;
+            }
+
+            let log_file = server.log_file.take().unwrap_or_else(|| format!("{}.log", name));
+            server.log_file = Some(Path::new(&*log_dir).join(log_file).display().to_string());
+        }
+        
+        let metrics = match (self.shepherd.metrics_tcp, self.shepherd.metrics_unix_socket) {
+            (Some(addr), _) => Some(MetricsListen::Tcp(addr)),
+            (None, Some(path)) => Some(MetricsListen::Unix(path)),
+            (None, None) => None,
+        };
         
         Config {
+            path: path,
+            daemon_log: Path::new(&*log_dir).join(DAEMON_LOG_FILE).display().to_string(),
             socket_path: socket_path,
+            pid_file: pid_file,
             start_servers: self.shepherd.start_servers,
-            servers: self.servers,
+            metrics: metrics,
+            servers: servers,
+            unknown_keys: unknown_keys,
         }    
     }    
 }
@@ -49,49 +152,1144 @@ impl TomlDecode {
 #[derive(RustcDecodable)]
 struct Shepherd {
     socket_path: Option<String>,
+    /// Defaults to `/var/log/shepherd` for root, otherwise `~/.local/state/shepherd`
+    log_dir: Option<String>,
+    /// Locked by the running daemon. Defaults to the socket path with `.pid` appended.
+    pid_file: Option<String>,
     start_servers: Vec<String>,             
+    /// An address to serve metrics on over HTTP, e.g. `"127.0.0.1:9100"`
+    metrics_tcp: Option<String>,
+    /// A socket to serve metrics on over HTTP. Only one of this and `metrics_tcp` may be set.
+    metrics_unix_socket: Option<String>,
 }
 
 pub struct Config {
+    /// The file this was loaded from
+    pub path: Path,
+    /// Where the daemon's output goes once it's in the background
+    pub daemon_log: String,
     pub socket_path: String,
+    pub pid_file: String,
     pub start_servers: Vec<String>,
+    pub metrics: Option<MetricsListen>,
     pub servers: HashMap<String, ServerConfig>,    
+    /// Keys in the file that don't mean anything, e.g. `servers.web.restart_polcy`
+    unknown_keys: Vec<String>,
+}
+
+/// Where to serve metrics
+#[derive(Clone, PartialEq, Show)]
+pub enum MetricsListen {
+    Tcp(String),
+    Unix(String),
 }
 
 impl Config {
-    pub fn load() -> IoResult<Config> {
-        let config_path = try!(find_config());
-        let config_file = try!(File::open(&config_path).read_to_string());        
-       
-        let toml_decode = try!(opt_to_toml_res(toml::decode_str::<TomlDecode>(&*config_file)));
+    pub fn load(config_path: &Path) -> IoResult<Config> {
+        let mut locations = HashMap::new();
+        let mut root = try!(read_toml(config_path, &mut locations));
+
+        try!(merge_includes(&mut root, config_path, &mut locations));
+        try!(apply_templates(&mut root));
+
+        let server_tables = match root.remove("servers") {
+            Some(Value::Table(tables)) => tables,
+            Some(_) => return Err(decode_error(format!("{}: `servers` must be a table", config_path.display()))),
+            None => BTreeMap::new(),
+        };
+
+        let mut servers = HashMap::new();
+        let mut without_logs = HashSet::new();
+        let mut unknown_keys = Vec::new();
+
+        for (name, mut table) in server_tables.into_iter() {
+            let location = locations.get(&name).cloned().unwrap_or_else(|| config_path.display().to_string());
+
+            if take_log_switch(&mut table) == Some(false) {
+                without_logs.insert(name.clone());
+            }
+
+            let mut decoder = toml::Decoder::new(table);
+            let server: ServerConfig = try!(Decodable::decode(&mut decoder).map_err(|err| {
+                decode_error(format!("{}: [servers.{}]: {}", location, name, err))
+            }));
+
+            // Anything the decoder didn't use is left behind
+            if let Some(ref rest) = decoder.toml {
+                find_keys(rest, &*format!("servers.{}", name), &mut unknown_keys);
+            }
+
+            servers.insert(name, server);
+        }
+
+        let mut decoder = toml::Decoder::new(Value::Table(root));
+        let toml_decode: TomlDecode = try!(Decodable::decode(&mut decoder).map_err(|err| {
+            decode_error(format!("{}: {}", config_path.display(), err))
+        }));
+
+        if let Some(ref rest) = decoder.toml {
+            find_keys(rest, "", &mut unknown_keys);
+        }
+
+        if toml_decode.shepherd.metrics_tcp.is_some() && toml_decode.shepherd.metrics_unix_socket.is_some() {
+            return Err(IoError {
+                kind: IoErrorKind::InvalidInput,
+                desc: "Invalid metrics listener",
+                detail: Some("Only one of `metrics_tcp` and `metrics_unix_socket` may be set".to_string()),
+            });
+        }
 
-        Ok(toml_decode.into_config())                              
+        let config = toml_decode.into_config(config_path.clone(), servers, without_logs, unknown_keys);
+        try!(config.check_dependencies());
+        try!(config.check_probes());
+        try!(config.check_restart_policies());
+        try!(config.check_credentials());
+        try!(config.check_limits());
+        try!(config.check_schedules());
+        try!(config.check_hooks());
+        try!(config.check_instances());
+
+        Ok(config)                              
     }    
+
+    /// Likely mistakes that don't stop the config from loading: unknown keys, `start_servers` without a config,
+    /// and servers whose `dir` or `command` don't exist.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = self.unknown_keys.iter().map(|key| format!("Unknown key `{}`", key)).collect();
+
+        for server in self.start_servers.iter() {
+            if self.instance_config(&**server).is_none() {
+                problems.push(format!("`start_servers` has \"{}\", but there is no [servers.{}]", server, server));
+            }
+        }
+
+        let mut servers: Vec<_> = self.servers.iter().collect();
+        servers.sort_by(|a, b| a.0.cmp(b.0));
+
+        for (name, config) in servers.into_iter() {
+            let dir = Path::new(&*config.dir);
+
+            if !dir.is_dir() {
+                problems.push(format!("\"{}\": `dir` {} is not a directory", name, dir.display()));
+            } else if let Err(msg) = check_command(&dir, config) {
+                problems.push(format!("\"{}\": {}", name, msg));
+            }
+        }
+
+        problems
+    }
+
+    /// The config for a server, or one instance of a server with `instances` set.
+    /// Each instance gets its own log file, e.g. `web@2.log`. Any instance number is accepted here,
+    /// the daemon checks it against the server's current scale.
+    pub fn instance_config(&self, name: &str) -> Option<ServerConfig> {
+        let (server, instance) = split_instance(name);
+
+        let mut config = match self.servers.get(server) {
+            Some(config) => config.clone(),
+            None => return None,
+        };
+
+        match instance {
+            Some(instance) if config.instances.is_some() && instance > 0 => {
+                if let Some(log_file) = config.log_file.take() {
+                    let path = Path::new(log_file);
+                    let file_name = format!(
+                        "{}{}{}{}",
+                        path.filestem_str().unwrap_or(""),
+                        INSTANCE_SEPARATOR,
+                        instance,
+                        path.extension_str().map_or(String::new(), |ext| format!(".{}", ext))
+                    );
+                    config.log_file = Some(path.with_filename(file_name).display().to_string());
+                }
+
+                Some(config)
+            },
+            Some(_) => None,
+            None => Some(config),
+        }
+    }
+
+    /// Order `servers` and everything they depend on so that each server comes after its dependencies,
+    /// and after any servers it is configured to start `after` that are also in the list.
+    pub fn start_order(&self, servers: &[String]) -> Vec<String> {
+        let mut wanted = HashSet::new();
+        let mut stack = servers.to_vec();
+
+        while let Some(server) = stack.pop() {
+            if let Some(config) = self.servers.get(&server) {
+                if !wanted.contains(&server) {
+                    stack.extend(config.dependencies().iter().cloned());
+                }
+            }
+
+            wanted.insert(server);
+        }
+
+        // Visit the given servers first, so independent servers keep their listed order
+        let mut extra: Vec<_> = wanted.iter().filter(|s| !servers.contains(*s)).cloned().collect();
+        extra.sort();
+
+        let mut visited = HashSet::new();
+        let mut order = Vec::new();
+
+        for server in servers.iter().chain(extra.iter()) {
+            self.visit_deps(&**server, &wanted, &mut visited, &mut order);
+        }
+
+        order
+    }
+
+    fn visit_deps(&self, server: &str, wanted: &HashSet<String>, visited: &mut HashSet<String>, order: &mut Vec<String>) {
+        if !visited.insert(server.to_owned()) { return; }
+
+        // Cycles are rejected at load time, so this terminates
+        if let Some(config) = self.servers.get(server) {
+            for dep in config.dependencies().iter().chain(config.start_after().iter()) {
+                if wanted.contains(dep) {
+                    self.visit_deps(&**dep, wanted, visited, order);
+                }
+            }
+        }
+
+        order.push(server.to_owned());
+    }
+
+    /// Get every server that directly or transitively `depends_on` `server`,
+    /// in the order they should be stopped.
+    pub fn dependents(&self, server: &str) -> Vec<String> {
+        let mut found = HashSet::new();
+        let mut stack = vec![server.to_owned()];
+
+        while let Some(dependency) = stack.pop() {
+            for (name, config) in self.servers.iter() {
+                if config.dependencies().contains(&dependency) && found.insert(name.clone()) {
+                    stack.push(name.clone());
+                }
+            }
+        }
+
+        let mut dependents: Vec<_> = found.iter().cloned().collect();
+        dependents.sort();
+
+        let mut order = self.start_order(&*dependents);
+        order.retain(|name| found.contains(name));
+        order.reverse();
+        order
+    }
+
+    fn check_dependencies(&self) -> IoResult<()> {
+        for (name, config) in self.servers.iter() {
+            for dep in config.dependencies().iter().chain(config.start_after().iter()) {
+                if !self.servers.contains_key(dep) {
+                    return Err(dependency_error(format!("\"{}\" depends on unknown server \"{}\"", name, dep)));
+                }
+            }
+        }
+
+        let mut names: Vec<_> = self.servers.keys().cloned().collect();
+        names.sort();
+
+        let mut done = HashSet::new();
+
+        for name in names.iter() {
+            let mut path = Vec::new();
+            if let Some(cycle) = self.find_cycle(&**name, &mut path, &mut done) {
+                return Err(dependency_error(format!("Dependency cycle: {}", cycle.connect(" -> "))));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn find_cycle(&self, server: &str, path: &mut Vec<String>, done: &mut HashSet<String>) -> Option<Vec<String>> {
+        if let Some(pos) = path.iter().position(|s| &**s == server) {
+            let mut cycle = path[pos..].to_vec();
+            cycle.push(server.to_owned());
+            return Some(cycle);
+        }
+
+        if done.contains(server) { return None; }
+
+        path.push(server.to_owned());
+
+        if let Some(config) = self.servers.get(server) {
+            for dep in config.dependencies().iter().chain(config.start_after().iter()) {
+                if let Some(cycle) = self.find_cycle(&**dep, path, done) {
+                    return Some(cycle);
+                }
+            }
+        }
+
+        path.pop();
+        done.insert(server.to_owned());
+
+        None
+    }
+
+    fn check_probes(&self) -> IoResult<()> {
+        for (name, config) in self.servers.iter() {
+            let probes = [("ready", &config.ready), ("health", &config.health)];
+
+            for &(kind, probe) in probes.iter() {
+                if let Some(ref probe) = *probe {
+                    if let Err(msg) = probe.validate() {
+                        return Err(IoError {
+                            kind: IoErrorKind::InvalidInput,
+                            desc: "Invalid probe",
+                            detail: Some(format!("\"{}\" {}: {}", name, kind, msg)),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_restart_policies(&self) -> IoResult<()> {
+        for (name, config) in self.servers.iter() {
+            if let Err(msg) = config.restart_policy() {
+                return Err(IoError {
+                    kind: IoErrorKind::InvalidInput,
+                    desc: "Invalid restart policy",
+                    detail: Some(format!("\"{}\": {}", name, msg)),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_credentials(&self) -> IoResult<()> {
+        for (name, config) in self.servers.iter() {
+            if let Err(msg) = Credentials::for_config(config) {
+                return Err(IoError {
+                    kind: IoErrorKind::InvalidInput,
+                    desc: "Invalid user or group",
+                    detail: Some(format!("\"{}\": {}", name, msg)),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_schedules(&self) -> IoResult<()> {
+        for (name, config) in self.servers.iter() {
+            for schedule in config.schedule.iter().flat_map(|schedules| schedules.iter()) {
+                if let Err(msg) = schedule.validate() {
+                    return Err(IoError {
+                        kind: IoErrorKind::InvalidInput,
+                        desc: "Invalid schedule",
+                        detail: Some(format!("\"{}\" schedule \"{}\": {}", name, schedule.cron, msg)),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_hooks(&self) -> IoResult<()> {
+        for (name, config) in self.servers.iter() {
+            let hooks = match config.hooks {
+                Some(ref hooks) => hooks,
+                None => continue,
+            };
+
+            let all = [
+                ("pre_start", &hooks.pre_start), ("post_start", &hooks.post_start), ("pre_stop", &hooks.pre_stop),
+                ("post_stop", &hooks.post_stop), ("on_crash", &hooks.on_crash),
+            ];
+
+            for &(event, hook) in all.iter() {
+                if hook.as_ref().map_or(false, |hook| hook.command.is_empty()) {
+                    return Err(IoError {
+                        kind: IoErrorKind::InvalidInput,
+                        desc: "Invalid hook",
+                        detail: Some(format!("\"{}\" {}: `command` needs at least a command", name, event)),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_instances(&self) -> IoResult<()> {
+        for (name, config) in self.servers.iter() {
+            let msg = if name.contains_char(INSTANCE_SEPARATOR) {
+                format!("server names can't contain `{}`", INSTANCE_SEPARATOR)
+            } else if config.instances == Some(0) {
+                "`instances` must be at least 1".to_string()
+            } else if config.port_base.is_some() && config.instances.is_none() {
+                "`port_base` needs `instances`".to_string()
+            } else {
+                continue;
+            };
+
+            return Err(IoError {
+                kind: IoErrorKind::InvalidInput,
+                desc: "Invalid instances",
+                detail: Some(format!("\"{}\": {}", name, msg)),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn check_limits(&self) -> IoResult<()> {
+        for (name, config) in self.servers.iter() {
+            if let Some(cpus) = config.limits.as_ref().and_then(|limits| limits.cpu_max) {
+                if cpus <= 0.0 {
+                    return Err(IoError {
+                        kind: IoErrorKind::InvalidInput,
+                        desc: "Invalid limits",
+                        detail: Some(format!("\"{}\": `cpu_max` must be more than 0", name)),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
-fn opt_to_toml_res<T>(opt: Option<T>) -> IoResult<T> {
-    opt.ok_or(
-        IoError {
-            kind: IoErrorKind::OtherIoError,
-            desc: "TOML file incorrectly formatted!",
-            detail: None,
+/// Split `web@2` into `("web", Some(2))`. Names without an instance number are returned as they are.
+pub fn split_instance(name: &str) -> (&str, Option<u32>) {
+    match name.rfind(INSTANCE_SEPARATOR) {
+        Some(sep) => match name[sep + 1..].parse() {
+            Some(instance) => (&name[..sep], Some(instance)),
+            None => (name, None),
+        },
+        None => (name, None),
+    }
+}
+
+/// Load the config at `path` and write any errors or problems with it to `w`, as `check-config` does.
+/// Returns `true` if there were none.
+pub fn write_check(path: &Path, w: &mut Writer) -> IoResult<bool> {
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(err) => {
+            try!(writeln!(w, "{}: {}", path.display(), describe_error(&err)));
+            return Ok(false);
+        },
+    };
+
+    let problems = config.problems();
+
+    if problems.is_empty() {
+        try!(writeln!(w, "{}: OK, {} servers", path.display(), config.servers.len()));
+        return Ok(true);
+    }
+
+    try!(writeln!(w, "{}: {} problem(s):", path.display(), problems.len()));
+
+    for problem in problems.iter() {
+        try!(writeln!(w, "    {}", problem));
+    }
+
+    Ok(false)
+}
+
+/// Describe an error loading the config, with each line of detail indented below it
+pub fn describe_error(err: &IoError) -> String {
+    match err.detail {
+        Some(ref detail) => format!("{}\n    {}", err.desc, detail.replace("\n", "\n    ")),
+        None => err.desc.to_owned(),
+    }
+}
+
+/// Check that `command` would be found and can be executed. Commands with variables in them aren't checked.
+fn check_command(dir: &Path, config: &ServerConfig) -> Result<(), String> {
+    let command = &*config.command;
+
+    if command.contains_char('$') {
+        return Ok(());
+    }
+
+    // Relative paths are resolved against the server's directory, like when it's started
+    if command.contains_char('/') {
+        let path = dir.join(command);
+
+        return if is_executable(&path) {
+            Ok(())
+        } else {
+            Err(format!("`command` {} is not an executable file", path.display()))
+        };
+    }
+
+    let search_path = config.env.as_ref().and_then(|env| env.get("PATH").cloned()).or_else(|| os::getenv("PATH"));
+
+    let found = search_path.map_or(false, |search_path| {
+        search_path.split(':').any(|dir| is_executable(&Path::new(dir).join(command)))
+    });
+
+    if found {
+        Ok(())
+    } else {
+        Err(format!("`command` \"{}\" was not found in PATH", command))
+    }
+}
+
+/// Add the full names of the values in `value` to `keys`
+fn find_keys(value: &Value, prefix: &str, keys: &mut Vec<String>) {
+    match *value {
+        Value::Table(ref table) => for (key, value) in table.iter() {
+            let name = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+            find_keys(value, &*name, keys);
+        },
+        Value::Array(ref values) => for value in values.iter() {
+            find_keys(value, prefix, keys);
+        },
+        _ => keys.push(prefix.to_owned()),
+    }
+}
+
+/// Parse a TOML file, reporting where any syntax errors are.
+/// Where each of its servers is defined is added to `locations`, see `server_location()`.
+fn read_toml(path: &Path, locations: &mut HashMap<String, String>) -> IoResult<toml::Table> {
+    let contents = try!(File::open(path).read_to_string());
+    let mut parser = toml::Parser::new(&*contents);
+
+    match parser.parse() {
+        Some(table) => {
+            if let Some(&Value::Table(ref servers)) = table.get("servers") {
+                for name in servers.keys() {
+                    locations.insert(name.clone(), server_location(path, &*contents, &parser, &**name));
+                }
+            }
+
+            Ok(table)
+        },
+        None => {
+            let errors: Vec<String> = parser.errors.iter()
+                .map(|err| {
+                    let (line, col) = parser.to_linecol(err.lo);
+                    format!("{}:{}:{}: {}", path.display(), line + 1, col + 1, err.desc)
+                })
+                .collect();
+
+            Err(IoError {
+                kind: IoErrorKind::InvalidInput,
+                desc: "TOML file incorrectly formatted!",
+                detail: Some(errors.connect("\n")),
+            })
+        },
+    }
+}
+
+/// Where the table for server `name` starts, as `path:line:column`, or just the path if it can't be found.
+fn server_location(path: &Path, contents: &str, parser: &toml::Parser, name: &str) -> String {
+    let headers = [
+        format!("[servers.{}]", name),
+        format!("[servers.\"{}\"]", name),
+        // Only sub-tables like `[servers.web.env]` are given
+        format!("[servers.{}.", name),
+    ];
+
+    match headers.iter().filter_map(|header| contents.find_str(&**header)).min() {
+        Some(offset) => {
+            let (line, col) = parser.to_linecol(offset);
+            format!("{}:{}:{}", path.display(), line + 1, col + 1)
+        },
+        None => path.display().to_string(),
+    }
+}
+
+/// Remove `log_file = true` or `false` from a server's table, since the decoder only accepts a path there,
+/// returning which it was. `true` is the same as leaving it out.
+fn take_log_switch(server: &mut Value) -> Option<bool> {
+    let server = match *server {
+        Value::Table(ref mut server) => server,
+        _ => return None,
+    };
+
+    let enabled = match server.get("log_file") {
+        Some(&Value::Boolean(enabled)) => enabled,
+        _ => return None,
+    };
+
+    server.remove("log_file");
+    Some(enabled)
+}
+
+fn decode_error(detail: String) -> IoError {
+    IoError {
+        kind: IoErrorKind::InvalidInput,
+        desc: "Invalid config",
+        detail: Some(detail),
+    }
+}
+
+/// Add the `servers` and `templates` of the files matched by `include` in the `[shepherd]` table to `root`.
+/// Names must be unique across all files, and where their servers are defined is added to `locations`.
+///
+/// `include` lists paths relative to the config file, which may contain `*` and `?`, e.g. `"conf.d/*.toml"`.
+/// It's taken out of the table, since it's only needed before decoding.
+fn merge_includes(root: &mut toml::Table, config_path: &Path, locations: &mut HashMap<String, String>)
+    -> IoResult<()> {
+    let include = match root.get_mut("shepherd") {
+        Some(&mut Value::Table(ref mut shepherd)) => shepherd.remove("include"),
+        _ => None,
+    };
+
+    let patterns = match include {
+        Some(Value::Array(patterns)) => patterns,
+        Some(_) => return Err(include_error("`include` must be an array of paths".to_string())),
+        None => return Ok(()),
+    };
+
+    let dir = config_path.dir_path();
+
+    for pattern in patterns.iter() {
+        let pattern = match pattern.as_str() {
+            Some(pattern) => pattern,
+            None => return Err(include_error("`include` must be an array of paths".to_string())),
+        };
+
+        for path in try!(glob(&dir.join(pattern))).iter() {
+            let mut included = try!(read_toml(path, locations));
+
+            for section in ["servers", "templates"].iter() {
+                let tables = match included.remove(*section) {
+                    Some(Value::Table(tables)) => tables,
+                    Some(_) => return Err(include_error(format!("{}: `{}` must be a table", path.display(), section))),
+                    None => continue,
+                };
+
+                if !root.contains_key(*section) {
+                    root.insert(section.to_string(), Value::Table(BTreeMap::new()));
+                }
+
+                let merged = match root.get_mut(*section) {
+                    Some(&mut Value::Table(ref mut merged)) => merged,
+                    _ => return Err(include_error(format!("`{}` must be a table", section))),
+                };
+
+                for (name, table) in tables.into_iter() {
+                    if merged.contains_key(&name) {
+                        return Err(include_error(format!(
+                            "{}: \"{}\" is already defined in `{}`", path.display(), name, section
+                        )));
+                    }
+
+                    merged.insert(name, table);
+                }
+            }
+
+            if let Some(key) = included.keys().next() {
+                return Err(include_error(format!(
+                    "{}: only `servers` and `templates` can be included, found `{}`", path.display(), key
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The files matching `pattern`, in order. Only the file name may contain wildcards.
+fn glob(pattern: &Path) -> IoResult<Vec<Path>> {
+    let file_pattern = pattern.filename_str().unwrap_or("");
+
+    if !file_pattern.contains_char('*') && !file_pattern.contains_char('?') {
+        return Ok(vec![pattern.clone()]);
+    }
+
+    let dir = pattern.dir_path();
+
+    // A missing directory is just an empty one, so `conf.d` can be left out until it's needed
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths: Vec<Path> = try!(fs::readdir(&dir)).into_iter()
+        .filter(|path| path.filename_str().map_or(false, |name| {
+            !name.starts_with(".") && wildcard_match(file_pattern.as_bytes(), name.as_bytes())
+        }))
+        .collect();
+
+    paths.sort_by(|a, b| a.as_vec().cmp(b.as_vec()));
+    Ok(paths)
+}
+
+/// `*` matches any run of characters, `?` any one character.
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&b'*') => (0..name.len() + 1).any(|skip| wildcard_match(&pattern[1..], &name[skip..])),
+        Some(&b'?') => !name.is_empty() && wildcard_match(&pattern[1..], &name[1..]),
+        Some(&c) => name.first() == Some(&c) && wildcard_match(&pattern[1..], &name[1..]),
+    }
+}
+
+fn include_error(detail: String) -> IoError {
+    IoError {
+        kind: IoErrorKind::InvalidInput,
+        desc: "Invalid include",
+        detail: Some(detail),
+    }
+}
+
+/// Resolve `extends` in each server, removing the `templates` table from `root`.
+fn apply_templates(root: &mut toml::Table) -> IoResult<()> {
+    let templates = match root.remove("templates") {
+        Some(Value::Table(templates)) => templates,
+        Some(_) => return Err(template_error("`templates` must be a table".to_string())),
+        None => BTreeMap::new(),
+    };
+
+    let servers = match root.get_mut("servers") {
+        Some(&mut Value::Table(ref mut servers)) => servers,
+        // Decoding will fail on anything else
+        _ => return Ok(()),
+    };
+
+    for (name, server) in servers.iter_mut() {
+        let server = match *server {
+            Value::Table(ref mut server) => server,
+            _ => continue,
+        };
+
+        let template = match server.remove("extends") {
+            Some(Value::String(template)) => template,
+            Some(_) => return Err(template_error(format!("\"{}\": `extends` must be a template name", name))),
+            None => continue,
+        };
+
+        let base = try!(
+            resolve_template(&templates, &*template, &mut Vec::new())
+                .map_err(|msg| template_error(format!("\"{}\": {}", name, msg)))
+        );
+
+        merge_table(server, base);
+    }
+
+    Ok(())
+}
+
+/// A template with the templates it extends merged in. `chain` holds the templates extending this one.
+fn resolve_template(templates: &toml::Table, name: &str, chain: &mut Vec<String>) -> Result<toml::Table, String> {
+    if chain.iter().any(|extending| &**extending == name) {
+        return Err(format!("templates extend each other: {} -> {}", chain.connect(" -> "), name));
+    }
+
+    let mut template = match templates.get(name) {
+        Some(&Value::Table(ref template)) => template.clone(),
+        Some(_) => return Err(format!("template \"{}\" must be a table", name)),
+        None => return Err(format!("unknown template \"{}\"", name)),
+    };
+
+    match template.remove("extends") {
+        Some(Value::String(parent)) => {
+            chain.push(name.to_string());
+            let base = try!(resolve_template(templates, &*parent, chain));
+            merge_table(&mut template, base);
+        },
+        Some(_) => return Err(format!("template \"{}\": `extends` must be a template name", name)),
+        None => (),
+    }
+
+    Ok(template)
+}
+
+/// Fill in what `table` doesn't set from `base`. Tables set in both, like `env` or `limits`,
+/// are merged the same way; any other value in `table` overrides the one in `base`.
+fn merge_table(table: &mut toml::Table, base: toml::Table) {
+    for (key, base_value) in base.into_iter() {
+        if let Some(&mut Value::Table(ref mut nested)) = table.get_mut(&key) {
+            if let Value::Table(base_nested) = base_value {
+                merge_table(nested, base_nested);
+            }
+            continue;
+        }
+
+        if !table.contains_key(&key) {
+            table.insert(key, base_value);
         }
-    )   
+    }
+}
+
+fn template_error(detail: String) -> IoError {
+    IoError {
+        kind: IoErrorKind::InvalidInput,
+        desc: "Invalid template",
+        detail: Some(detail),
+    }
+}
+
+fn dependency_error(detail: String) -> IoError {
+    IoError {
+        kind: IoErrorKind::InvalidInput,
+        desc: "Invalid server dependencies",
+        detail: Some(detail),
+    }
 }
 
-#[derive(Clone, RustcDecodable)]
+#[derive(Clone, PartialEq, RustcDecodable)]
 pub struct ServerConfig {
     pub dir: String,
     pub command: String,
+    /// May refer to environment variables as `${NAME}` or `${NAME:-default}`
     pub args: Vec<String>,
+    /// Variables set for the server, overriding `env_file` and the daemon's environment
+    pub env: Option<HashMap<String, String>>,
+    /// A dotenv-style file of `KEY=value` lines, relative to `dir`
+    pub env_file: Option<String>,
+    /// Don't pass on the daemon's environment
+    pub clear_env: Option<bool>,
+    /// A user name or ID to run the server as. Needs the daemon to run as root.
+    pub user: Option<String>,
+    /// A group name or ID. Defaults to the primary group of `user`.
+    pub group: Option<String>,
+    /// Supplementary group names or IDs. If `user` or `group` is set, defaults to just the primary group.
+    pub groups: Option<Vec<String>>,
+    /// An octal string, e.g. `"027"`
+    pub umask: Option<String>,
+    /// Resource limits for the server's process
+    pub limits: Option<LimitsConfig>,
+    /// Time between resource usage samples, in seconds
+    pub stats_interval: Option<u64>,
+    /// How many samples to keep
+    pub stats_history: Option<usize>,
+    /// Actions run at times given by cron expressions
+    pub schedule: Option<Vec<ScheduleConfig>>,
+    /// Commands run at points in the server's lifecycle
+    pub hooks: Option<HooksConfig>,
+    /// Run this many replicas, named `<server>@1` to `<server>@N`. Each gets its number in `INSTANCE`.
+    pub instances: Option<u32>,
+    /// Replicas get `PORT` set to this plus their number minus one
+    pub port_base: Option<u32>,
+    /// Superseded by `restart`; `true` is the same as `restart = "always"`
     pub auto_restart: Option<bool>,
+    /// `"never"`, `"on-failure"` or `"always"`
+    pub restart: Option<String>,
+    /// Exit codes that `on-failure` doesn't count as failures. Defaults to `[0]`.
+    pub success_exit_codes: Option<Vec<isize>>,
+    /// Delay before the first restart after a crash, doubled for each restart after. In seconds.
+    pub restart_backoff: Option<u64>,
+    /// The longest the restart delay can get, in seconds
+    pub restart_backoff_max: Option<u64>,
+    /// Give up restarting after this many restarts within `restart_window`
+    pub max_restarts: Option<u32>,
+    /// In seconds. A server that runs this long without crashing has its backoff reset.
+    pub restart_window: Option<u64>,
     pub on_stop: Vec<String>,
     pub stop_timeout: Option<u64>,
+    /// Defaults to `<log_dir>/<server>.log`. `false` keeps only the lines held in memory.
+    pub log_file: Option<String>,
+    /// In bytes
+    pub log_rotate_size: Option<u64>,
+    /// In seconds
+    pub log_rotate_interval: Option<u64>,
+    pub log_retain: Option<usize>,
+    pub log_compress: Option<bool>,
+    /// Servers that must be running (and ready) before this one starts
+    pub depends_on: Option<Vec<String>>,
+    /// Servers that, if they're being started too, should be started first
+    pub after: Option<Vec<String>>,
+    /// How to tell the server has finished starting up
+    pub ready: Option<ProbeConfig>,
+    /// How to tell the server is still working once it's ready
+    pub health: Option<ProbeConfig>,
+}
+
+/// Used for both readiness and health checks.
+/// Exactly one of `log_pattern`, `tcp`, `unix_socket` and `exec` must be set.
+#[derive(Clone, PartialEq, RustcDecodable, Show)]
+pub struct ProbeConfig {
+    /// A regex matched against each output line.
+    /// As a health check, passes if a matching line was printed since the last check.
+    pub log_pattern: Option<String>,
+    /// An address to connect to, e.g. `"127.0.0.1:25565"`
+    pub tcp: Option<String>,
+    pub unix_socket: Option<String>,
+    /// A command and its arguments, which should exit successfully
+    pub exec: Option<Vec<String>>,
+    /// In seconds. For readiness, how long the server has to pass; for health checks,
+    /// how long each check may take.
+    pub timeout: Option<u64>,
+    /// Time between attempts, in seconds.
+    pub interval: Option<u64>,
+    /// Health checks only: how many checks in a row must fail before the server is considered hung.
+    pub failure_threshold: Option<u32>,
+    /// Health checks only: whether to restart a hung server. Defaults to `true`.
+    pub restart: Option<bool>,
+}
+
+impl ProbeConfig {
+    /// Check that exactly one kind of probe is configured, and that it can be used.
+    pub fn validate(&self) -> Result<(), String> {
+        let kinds = [self.log_pattern.is_some(), self.tcp.is_some(), self.unix_socket.is_some(), self.exec.is_some()];
+
+        match kinds.iter().filter(|&&set| set).count() {
+            1 => (),
+            0 => return Err("No probe set; expected one of `log_pattern`, `tcp`, `unix_socket` or `exec`".to_string()),
+            _ => return Err("Only one of `log_pattern`, `tcp`, `unix_socket` or `exec` may be set".to_string()),
+        }
+
+        if let Some(ref pattern) = self.log_pattern {
+            try!(Regex::new(&**pattern).map_err(|err| format!("Invalid log_pattern: {}", err)));
+        }
+
+        if self.exec.as_ref().map_or(false, |command| command.is_empty()) {
+            return Err("`exec` needs at least a command".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Each hook is run in the server's directory with its environment, as its `user` and `group`.
+/// `pre_start` and `pre_stop` are waited for, and the daemon does nothing else for up to their timeout,
+/// which is 10 seconds at most; the others run in the background.
+#[derive(Clone, PartialEq, RustcDecodable, Show)]
+pub struct HooksConfig {
+    /// If this fails, the server isn't started
+    pub pre_start: Option<HookConfig>,
+    pub post_start: Option<HookConfig>,
+    pub pre_stop: Option<HookConfig>,
+    pub post_stop: Option<HookConfig>,
+    /// Run when the server exits unsuccessfully, per `success_exit_codes`, without being stopped
+    pub on_crash: Option<HookConfig>,
+}
+
+#[derive(Clone, PartialEq, RustcDecodable, Show)]
+pub struct HookConfig {
+    /// A command and its arguments
+    pub command: Vec<String>,
+    /// In seconds. The hook is killed after this long, which counts as failing.
+    /// Defaults to 60, or 10 for `pre_start` and `pre_stop`.
+    pub timeout: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, RustcDecodable, Show)]
+pub struct ScheduleConfig {
+    /// e.g. `"0 4 * * *"`, in local time
+    pub cron: String,
+    /// `"start"`, `"stop"`, `"restart"`, `"send"` or `"hook"`
+    pub action: String,
+    /// For `send`: the line to send to the server
+    pub command: Option<String>,
+    /// For `hook`: a command and its arguments, run in the server's directory
+    pub hook: Option<Vec<String>>,
+    /// For `hook`: how long it may run, in seconds
+    pub timeout: Option<u64>,
+}
+
+impl ScheduleConfig {
+    /// Check that the cron expression parses and the action has what it needs.
+    pub fn validate(&self) -> Result<(), String> {
+        try!(Cron::parse(&*self.cron));
+
+        match &*self.action {
+            "start" | "stop" | "restart" => Ok(()),
+            "send" if self.command.is_none() => Err("The `send` action needs a `command`".to_string()),
+            "send" => Ok(()),
+            "hook" if self.hook.as_ref().map_or(true, |hook| hook.is_empty()) => {
+                Err("The `hook` action needs a `hook` with at least a command".to_string())
+            },
+            "hook" => Ok(()),
+            other => Err(format!(
+                "Unknown action \"{}\", expected \"start\", \"stop\", \"restart\", \"send\" or \"hook\"", other
+            )),
+        }
+    }
+}
+
+/// `open_files`, `core_size` and `address_space` are set with `setrlimit()`. The rest need the daemon
+/// to have been delegated a cgroup v2 subtree, and are skipped with a warning otherwise.
+#[derive(Clone, PartialEq, RustcDecodable, Show)]
+pub struct LimitsConfig {
+    pub open_files: Option<u64>,
+    /// In bytes. `0` disables core dumps.
+    pub core_size: Option<u64>,
+    /// In bytes
+    pub address_space: Option<u64>,
+    /// cgroup `memory.max`, in bytes
+    pub memory_max: Option<u64>,
+    /// cgroup `cpu.max`, as a number of CPUs, e.g. `0.5`
+    pub cpu_max: Option<f64>,
+    /// cgroup `pids.max`
+    pub pids_max: Option<u64>,
+}
+
+impl LimitsConfig {
+    pub fn needs_cgroup(&self) -> bool {
+        self.memory_max.is_some() || self.cpu_max.is_some() || self.pids_max.is_some()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+static NO_SERVERS: &'static [String] = &[];
+
+/// Push the name of each field that differs between `$a` and `$b` to `$changed`
+macro_rules! diff_fields {
+    ($a:expr, $b:expr, $changed:expr; $($field:ident),+) => {
+        $(
+            if $a.$field != $b.$field {
+                $changed.push(stringify!($field));
+            }
+        )+
+    }
+}
+
+impl ServerConfig {
+    pub fn restart_policy(&self) -> Result<RestartPolicy, String> {
+        match self.restart.as_ref().map(|s| &**s) {
+            Some("never") => Ok(RestartPolicy::Never),
+            Some("on-failure") => Ok(RestartPolicy::OnFailure),
+            Some("always") => Ok(RestartPolicy::Always),
+            Some(other) => Err(format!("Unknown restart policy \"{}\", expected \"never\", \"on-failure\" or \"always\"", other)),
+            None if self.auto_restart.unwrap_or(false) => Ok(RestartPolicy::Always),
+            None => Ok(RestartPolicy::Never),
+        }
+    }
+
+    /// The fields that differ in `other`: first those only used when the process is started,
+    /// then those that can be applied to a running instance. `env_file` is compared by path, not contents.
+    pub fn changes(&self, other: &ServerConfig) -> (Vec<&'static str>, Vec<&'static str>) {
+        let mut spawn = Vec::new();
+        let mut running = Vec::new();
+
+        diff_fields!(self, other, spawn;
+            dir, command, args, env, env_file, clear_env, user, group, groups, umask, limits, instances, port_base,
+            log_file, log_rotate_size, log_rotate_interval, log_retain, log_compress
+        );
+
+        diff_fields!(self, other, running;
+            stats_interval, stats_history, schedule, hooks, auto_restart, restart, success_exit_codes,
+            restart_backoff, restart_backoff_max, max_restarts, restart_window, on_stop, stop_timeout,
+            depends_on, after, ready, health
+        );
+
+        (spawn, running)
+    }
+
+    pub fn dependencies(&self) -> &[String] {
+        self.depends_on.as_ref().map_or(NO_SERVERS, |deps| &**deps)
+    }
+
+    pub fn start_after(&self) -> &[String] {
+        self.after.as_ref().map_or(NO_SERVERS, |after| &**after)
+    }
 }
 
 impl Show for ServerConfig {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        fmt.write_fmt(format_args!("directory: {}\ncommand: {}\nargs: {:?}", self.dir, self.command, self.args))
+        try!(fmt.write_fmt(format_args!("directory: {}\ncommand: {}\nargs: {:?}", self.dir, self.command, self.args)));
+
+        if !self.dependencies().is_empty() {
+            try!(fmt.write_fmt(format_args!("\ndepends on: {:?}", self.dependencies())));
+        }
+
+        if !self.start_after().is_empty() {
+            try!(fmt.write_fmt(format_args!("\nstarts after: {:?}", self.start_after())));
+        }
+
+        if let Some(instances) = self.instances {
+            try!(fmt.write_fmt(format_args!("\ninstances: {}", instances)));
+        }
+
+        if let Some(ref env) = self.env {
+            let mut keys: Vec<&String> = env.keys().collect();
+            keys.sort();
+            try!(fmt.write_fmt(format_args!("\nenv: {:?}", keys)));
+        }
+
+        if let Some(ref env_file) = self.env_file {
+            try!(fmt.write_fmt(format_args!("\nenv file: {}", env_file)));
+        }
+
+        if self.clear_env.unwrap_or(false) {
+            try!(fmt.write_fmt(format_args!("\nenvironment cleared")));
+        }
+
+        if let Some(ref user) = self.user {
+            try!(fmt.write_fmt(format_args!("\nuser: {}", user)));
+        }
+
+        if let Some(ref group) = self.group {
+            try!(fmt.write_fmt(format_args!("\ngroup: {}", group)));
+        }
+
+        if let Some(ref log_file) = self.log_file {
+            try!(fmt.write_fmt(format_args!("\nlog file: {}", log_file)));
+        }
+
+        Ok(())
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::{merge_table, wildcard_match};
+
+    use toml;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        wildcard_match(pattern.as_bytes(), name.as_bytes())
+    }
+
+    fn table(contents: &str) -> toml::Table {
+        toml::Parser::new(contents).parse().unwrap()
+    }
+
+    #[test]
+    fn literal_names() {
+        assert!(matches("web.toml", "web.toml"));
+        assert!(!matches("web.toml", "api.toml"));
+        assert!(!matches("web.toml", "web.toml.bak"));
+        assert!(!matches("", "web.toml"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.toml", "web.toml"));
+        assert!(matches("*.toml", ".toml"));
+        assert!(!matches("*.toml", "web.toml.bak"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbY"));
+
+        assert!(matches("web-?.toml", "web-1.toml"));
+        assert!(!matches("web-?.toml", "web-.toml"));
+        assert!(!matches("web-?.toml", "web-10.toml"));
+    }
+
+    #[test]
+    fn merge_fills_in_unset_keys() {
+        let mut server = table("command = \"./api\"\ndir = \"/srv/api\"");
+        merge_table(&mut server, table("command = \"./base\"\nrestart_policy = \"always\""));
+
+        assert_eq!(server, table("command = \"./api\"\ndir = \"/srv/api\"\nrestart_policy = \"always\""));
+    }
+
+    #[test]
+    fn merge_nested_tables() {
+        let mut server = table("[env]\nPORT = \"8080\"\n[limits]\nopen_files = 1024");
+        merge_table(&mut server, table("[env]\nPORT = \"80\"\nMODE = \"prod\"\n[limits]\ncore_size = 0"));
+
+        assert_eq!(server, table("[env]\nPORT = \"8080\"\nMODE = \"prod\"\n[limits]\nopen_files = 1024\ncore_size = 0"));
+    }
+
+    #[test]
+    fn merge_keeps_other_values() {
+        // Arrays aren't merged, and a table in the base doesn't replace a value that isn't one
+        let mut server = table("args = [\"--fast\"]\nenv = \"not a table\"");
+        merge_table(&mut server, table("args = [\"--slow\", \"--safe\"]\n[env]\nPORT = \"80\""));
+
+        assert_eq!(server, table("args = [\"--fast\"]\nenv = \"not a table\""));
+    }
+}
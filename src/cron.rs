@@ -0,0 +1,192 @@
+//! Cron expressions: `minute hour day-of-month month day-of-week`.
+//!
+//! Fields accept `*`, numbers, ranges (`1-5`), lists (`1,15`) and steps (`*/10`, `0-30/5`).
+//! `@hourly`, `@daily`, `@midnight`, `@weekly`, `@monthly` and `@yearly` are also accepted.
+//! As in cron, if both the day of month and the day of week are restricted, either may match.
+
+use time::Tm;
+
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    /// Sunday is 0
+    weekdays: Vec<bool>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Cron, String> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+
+        let fields: Vec<&str> = expr.words().collect();
+
+        if fields.len() != 5 {
+            return Err(format!("Expected 5 fields in cron expression \"{}\", found {}", expr, fields.len()));
+        }
+
+        let mut weekdays = try!(parse_field(fields[4], 0, 7).map_err(|msg| format!("day of week: {}", msg)));
+        // 7 is also Sunday
+        if weekdays.pop() == Some(true) {
+            weekdays[0] = true;
+        }
+
+        Ok(Cron {
+            minutes: try!(parse_field(fields[0], 0, 59).map_err(|msg| format!("minute: {}", msg))),
+            hours: try!(parse_field(fields[1], 0, 23).map_err(|msg| format!("hour: {}", msg))),
+            days: try!(parse_field(fields[2], 1, 31).map_err(|msg| format!("day of month: {}", msg))),
+            months: try!(parse_field(fields[3], 1, 12).map_err(|msg| format!("month: {}", msg))),
+            weekdays: weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// Whether the expression matches the minute of `tm`.
+    pub fn matches(&self, tm: &Tm) -> bool {
+        let day = self.days[tm.tm_mday as usize - 1];
+        let weekday = self.weekdays[tm.tm_wday as usize];
+
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        self.minutes[tm.tm_min as usize] && self.hours[tm.tm_hour as usize]
+            && self.months[tm.tm_mon as usize] && day_matches
+    }
+}
+
+/// Parse one field into a set of flags, the first of which stands for `min`.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut set: Vec<bool> = (min..max + 1).map(|_| false).collect();
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(slash) => match part[slash + 1..].parse::<u32>() {
+                Some(step) if step > 0 => (&part[..slash], step),
+                _ => return Err(format!("invalid step in \"{}\"", part)),
+            },
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            let mut bounds = range.splitn(1, '-');
+            let start = bounds.next().and_then(|start| start.parse::<u32>());
+            let end = match bounds.next() {
+                Some(end) => end.parse::<u32>(),
+                // `5/10` means from 5 to the end
+                None if step > 1 => Some(max),
+                None => start,
+            };
+
+            match (start, end) {
+                (Some(start), Some(end)) if start >= min && end <= max && start <= end => (start, end),
+                _ => return Err(format!("\"{}\" is not a value or range within {}-{}", part, min, max)),
+            }
+        };
+
+        let mut value = start;
+        while value <= end {
+            set[(value - min) as usize] = true;
+            value += step;
+        }
+    }
+
+    Ok(set)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cron, parse_field};
+
+    use time::{self, Timespec, Tm};
+
+    /// Thursday 2015-01-01 00:00 UTC
+    const NEW_YEAR: i64 = 1420070400;
+    const HOUR: i64 = 60 * 60;
+    const DAY: i64 = 24 * HOUR;
+
+    fn utc(secs: i64) -> Tm {
+        time::at_utc(Timespec::new(secs, 0))
+    }
+
+    /// The values a field selects
+    fn values(field: &str, min: u32, max: u32) -> Vec<u32> {
+        parse_field(field, min, max).unwrap().iter().enumerate()
+            .filter(|&(_, &on)| on)
+            .map(|(index, _)| index as u32 + min)
+            .collect()
+    }
+
+    #[test]
+    fn values_ranges_and_lists() {
+        assert_eq!(values("*", 1, 12), (1..13).collect::<Vec<u32>>());
+        assert_eq!(values("5", 0, 59), vec![5]);
+        assert_eq!(values("1-3", 1, 31), vec![1, 2, 3]);
+        assert_eq!(values("15,1,3-4", 1, 31), vec![1, 3, 4, 15]);
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(values("*/15", 0, 59), vec![0, 15, 30, 45]);
+        assert_eq!(values("0-30/10", 0, 59), vec![0, 10, 20, 30]);
+        assert_eq!(values("50/5", 0, 59), vec![50, 55]);
+        assert_eq!(values("1-5/1", 0, 6), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn invalid_fields() {
+        for field in ["60", "5-1", "*/0", "*/x", "1-", "a", "1,,2", ""].iter() {
+            assert!(parse_field(*field, 0, 59).is_err(), "{:?} should be rejected", field);
+        }
+    }
+
+    #[test]
+    fn expressions() {
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("* * * * * *").is_err());
+        assert!(Cron::parse("0 24 * * *").is_err());
+        assert!(Cron::parse("0 0 0 * *").is_err());
+        assert!(Cron::parse("@fortnightly").is_err());
+        assert!(Cron::parse(" @daily ").is_ok());
+    }
+
+    #[test]
+    fn matching() {
+        let daily = Cron::parse("@daily").unwrap();
+        assert!(daily.matches(&utc(NEW_YEAR)));
+        assert!(!daily.matches(&utc(NEW_YEAR + 60)));
+
+        // Sunday 2015-01-04 12:30, as 0 and as 7
+        let sunday = utc(NEW_YEAR + 3 * DAY + 12 * HOUR + 30 * 60);
+        assert!(Cron::parse("30 12 * * 0").unwrap().matches(&sunday));
+        assert!(Cron::parse("30 12 * * 7").unwrap().matches(&sunday));
+        assert!(!Cron::parse("30 12 * * 1-6").unwrap().matches(&sunday));
+        assert!(!Cron::parse("30 12 * 2 *").unwrap().matches(&sunday));
+    }
+
+    #[test]
+    fn day_of_month_or_week() {
+        // With both restricted either matches: the 1st, or any Monday
+        let cron = Cron::parse("0 0 1 * 1").unwrap();
+        assert!(cron.matches(&utc(NEW_YEAR)));
+        assert!(cron.matches(&utc(NEW_YEAR + 4 * DAY)));
+        assert!(!cron.matches(&utc(NEW_YEAR + 5 * DAY)));
+
+        // With one restricted only that one counts
+        assert!(!Cron::parse("0 0 1 * *").unwrap().matches(&utc(NEW_YEAR + 4 * DAY)));
+        assert!(!Cron::parse("0 0 * * 1").unwrap().matches(&utc(NEW_YEAR)));
+    }
+}
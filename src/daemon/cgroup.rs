@@ -0,0 +1,246 @@
+//! Per-server cgroup v2 limits, when the daemon has been delegated a cgroup subtree.
+//!
+//! cgroup v2 only lets leaf cgroups hold processes once controllers are enabled for their children,
+//! so the daemon moves itself, and anything else in its cgroup, into a `shepherd-daemon` leaf of its own
+//! cgroup. That happens at startup if any server has limits, before servers are started, or otherwise the
+//! first time limits are needed. Each server then gets a `server-<name>` sibling.
+
+use config::LimitsConfig;
+
+use libc::{c_char, c_int, c_void, mode_t, size_t, ssize_t};
+
+use std::ffi::CString;
+use std::io::{File, IoError, IoErrorKind, IoResult};
+use std::io::fs::{self, PathExtensions};
+use std::io;
+use std::os;
+
+const CGROUP_MOUNT: &'static str = "/sys/fs/cgroup";
+const DAEMON_LEAF: &'static str = "shepherd-daemon";
+const CONTROLLERS: &'static str = "+memory +cpu +pids";
+const CPU_PERIOD: u64 = 100000;
+/// Processes can fork while they're being moved, so moving them is retried this many times
+const MOVE_ATTEMPTS: u32 = 5;
+
+const EBUSY: i32 = 16;
+
+const O_WRONLY: c_int = 1;
+const O_CLOEXEC: c_int = 0o2000000;
+
+extern {
+    fn open(path: *const c_char, flags: c_int, mode: mode_t) -> c_int;
+    fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t;
+    fn close(fd: c_int) -> c_int;
+}
+
+pub struct Cgroup {
+    path: Path,
+    /// Event counts when the process started, so only its own limit hits are reported
+    oom_kills: u64,
+    pids_max_hits: u64,
+}
+
+impl Cgroup {
+    /// Set up the server's cgroup with the configured limits, or return `None` if it doesn't need one.
+    pub fn for_server(server: &str, limits: &LimitsConfig) -> IoResult<Option<Cgroup>> {
+        if !limits.needs_cgroup() {
+            return Ok(None);
+        }
+
+        let root = try!(delegated_root());
+        let path = root.join(format!("server-{}", server));
+
+        if !path.exists() {
+            try!(fs::mkdir(&path, io::USER_RWX));
+        }
+
+        if let Some(memory) = limits.memory_max {
+            try!(write_file(&path.join("memory.max"), &*memory.to_string()));
+        }
+
+        if let Some(cpus) = limits.cpu_max {
+            let quota = (cpus * CPU_PERIOD as f64) as u64;
+            try!(write_file(&path.join("cpu.max"), &*format!("{} {}", quota, CPU_PERIOD)));
+        }
+
+        if let Some(pids) = limits.pids_max {
+            try!(write_file(&path.join("pids.max"), &*pids.to_string()));
+        }
+
+        let mut cgroup = Cgroup {
+            path: path,
+            oom_kills: 0,
+            pids_max_hits: 0,
+        };
+
+        cgroup.oom_kills = cgroup.read_event("memory.events", "oom_kill");
+        cgroup.pids_max_hits = cgroup.read_event("pids.events", "max");
+
+        Ok(Some(cgroup))
+    }
+
+    /// The `cgroup.procs` file the process should add itself to.
+    pub fn procs_path(&self) -> String {
+        self.path.join("cgroup.procs").display().to_string()
+    }
+
+    /// Describe any limits that were hit since the process started.
+    pub fn limit_hits(&self) -> Option<String> {
+        let mut hits = Vec::new();
+
+        let oom_kills = self.read_event("memory.events", "oom_kill") - self.oom_kills;
+        if oom_kills > 0 {
+            hits.push(format!("memory.max reached, {} OOM kill(s)", oom_kills));
+        }
+
+        let pids_max_hits = self.read_event("pids.events", "max") - self.pids_max_hits;
+        if pids_max_hits > 0 {
+            hits.push(format!("pids.max reached {} time(s)", pids_max_hits));
+        }
+
+        if hits.is_empty() { None } else { Some(hits.connect(", ")) }
+    }
+
+    /// Read a counter from a `*.events` file, which has lines like `oom_kill 2`.
+    fn read_event(&self, file: &str, event: &str) -> u64 {
+        let contents = File::open(&self.path.join(file)).read_to_string().unwrap_or(String::new());
+
+        contents.lines()
+            .filter_map(|line| {
+                let mut words = line.words();
+                match (words.next(), words.next()) {
+                    (Some(name), Some(count)) if name == event => count.parse(),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Fails if a restarted process is still using it, which is fine
+        let _ = fs::rmdir(&self.path);
+    }
+}
+
+/// Move the daemon into its leaf and enable controllers for servers. Call before starting any server.
+pub fn prepare() -> IoResult<()> {
+    delegated_root().map(|_| ())
+}
+
+/// Find the cgroup the daemon was delegated, moving the daemon into its own leaf the first time.
+fn delegated_root() -> IoResult<Path> {
+    let own = try!(own_cgroup());
+
+    if own.filename_str() == Some(DAEMON_LEAF) {
+        return Ok(own.dir_path());
+    }
+
+    let leaf = own.join(DAEMON_LEAF);
+
+    if !leaf.exists() {
+        try!(fs::mkdir(&leaf, io::USER_RWX).map_err(not_delegated));
+    }
+
+    // Moves every thread of the daemon
+    let pid = unsafe { ::libc::getpid() };
+    try!(write_file(&leaf.join("cgroup.procs"), &*pid.to_string()).map_err(not_delegated));
+
+    // Servers started without limits, and their children, are still in our cgroup
+    try!(move_processes(&own, &leaf));
+
+    try!(write_file_errno(&own.join("cgroup.subtree_control"), CONTROLLERS).map_err(|(err, errno)| {
+        if errno == EBUSY { busy(err.to_string()) } else { not_delegated(err) }
+    }));
+
+    Ok(own)
+}
+
+/// Move every process in `from` to `to`.
+fn move_processes(from: &Path, to: &Path) -> IoResult<()> {
+    let procs = from.join("cgroup.procs");
+
+    for _ in 0..MOVE_ATTEMPTS {
+        let pids = try!(File::open(&procs).read_to_string());
+
+        if pids.trim().is_empty() {
+            return Ok(());
+        }
+
+        for pid in pids.lines() {
+            // Processes can exit while we're moving them
+            let _ = write_file(&to.join("cgroup.procs"), pid.trim());
+        }
+    }
+
+    let left = try!(File::open(&procs).read_to_string());
+
+    if left.trim().is_empty() {
+        Ok(())
+    } else {
+        let left: Vec<&str> = left.words().collect();
+        Err(busy(format!("processes {} are still in {}", left.connect(", "), from.display())))
+    }
+}
+
+/// The daemon's cgroup, from the `0::/path` line of `/proc/self/cgroup`.
+fn own_cgroup() -> IoResult<Path> {
+    let contents = try!(File::open(&Path::new("/proc/self/cgroup")).read_to_string());
+
+    for line in contents.lines() {
+        if line.starts_with("0::") {
+            return Ok(Path::new(CGROUP_MOUNT).join(line[3..].trim_left_matches('/')));
+        }
+    }
+
+    Err(IoError {
+        kind: IoErrorKind::OtherIoError,
+        desc: "cgroup v2 is not available",
+        detail: None,
+    })
+}
+
+fn write_file(path: &Path, contents: &str) -> IoResult<()> {
+    File::create(path).and_then(|mut file| file.write_str(contents))
+}
+
+/// Like `write_file()`, but a failure comes with its errno, which `IoError` doesn't keep.
+/// It's read straight after the call that failed, before anything else can change it.
+fn write_file_errno(path: &Path, contents: &str) -> Result<(), (IoError, i32)> {
+    let path_c = CString::from_slice(path.as_vec());
+
+    let fd = unsafe { open(path_c.as_ptr(), O_WRONLY | O_CLOEXEC, 0) };
+    if fd == -1 {
+        let errno = os::errno() as i32;
+        return Err((IoError::from_errno(errno as usize, false), errno));
+    }
+
+    let written = unsafe { write(fd, contents.as_ptr() as *const c_void, contents.len() as size_t) };
+    let errno = os::errno() as i32;
+    unsafe { close(fd); }
+
+    if written == -1 {
+        Err((IoError::from_errno(errno as usize, false), errno))
+    } else {
+        Ok(())
+    }
+}
+
+/// The daemon's cgroup still holds processes, so controllers can't be enabled for its children
+fn busy(detail: String) -> IoError {
+    IoError {
+        kind: IoErrorKind::ResourceUnavailable,
+        desc: "Processes are left in the daemon's cgroup",
+        detail: Some(detail),
+    }
+}
+
+fn not_delegated(err: IoError) -> IoError {
+    IoError {
+        kind: err.kind,
+        desc: "The daemon hasn't been delegated a cgroup to manage",
+        detail: Some(err.to_string()),
+    }
+}
@@ -0,0 +1,173 @@
+//! Detaching the daemon from whoever started it, and making sure only one daemon runs per pidfile.
+//!
+//! `start-daemon` first claims the pidfile and socket, so a second daemon fails while its error can still be
+//! seen. It then forks twice, with `setsid()` in between, so the daemon is reparented to init and can never
+//! reacquire a controlling terminal. Its output goes to its log file. The working directory is kept,
+//! since server `dir`s and other paths in the config may be relative to it.
+
+use config::Config;
+
+use libc::{c_char, c_int, c_void, mode_t, pid_t, size_t, ssize_t};
+
+use std::ffi::CString;
+use std::io::{self, File, IoError, IoErrorKind, IoResult};
+use std::io::fs::{self, PathExtensions};
+use std::io::net::pipe::UnixStream;
+use std::os;
+
+const O_RDWR: c_int = 2;
+const O_WRONLY: c_int = 1;
+const O_CREAT: c_int = 0o100;
+const O_APPEND: c_int = 0o2000;
+const O_CLOEXEC: c_int = 0o2000000;
+const O_NOFOLLOW: c_int = 0o400000;
+
+const LOCK_EX: c_int = 2;
+const LOCK_NB: c_int = 4;
+const EWOULDBLOCK: i32 = 11;
+
+extern {
+    fn fork() -> pid_t;
+    fn setsid() -> pid_t;
+    fn open(path: *const c_char, flags: c_int, mode: mode_t) -> c_int;
+    fn dup2(old: c_int, new: c_int) -> c_int;
+    fn close(fd: c_int) -> c_int;
+    fn flock(fd: c_int, operation: c_int) -> c_int;
+    fn ftruncate(fd: c_int, length: i64) -> c_int;
+    fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t;
+    fn _exit(status: c_int) -> !;
+}
+
+/// Make sure no other daemon is running with `config`: lock its pidfile, and check nothing is listening on
+/// its socket, which a daemon using another pidfile could be.
+pub fn claim(config: &Config) -> IoResult<PidFile> {
+    let pid_file = try!(PidFile::lock(Path::new(&*config.pid_file)));
+    let socket_path = Path::new(&*config.socket_path);
+
+    if socket_path.exists() && UnixStream::connect(&socket_path).is_ok() {
+        return Err(IoError {
+            kind: IoErrorKind::ResourceUnavailable,
+            desc: "A daemon is already running",
+            detail: Some(format!("Something is listening on {}", socket_path.display())),
+        });
+    }
+
+    Ok(pid_file)
+}
+
+/// Fork into the background, returning only in the daemon, and write its PID to `pid_file`, which it keeps
+/// the lock on. Standard output and error go to `log_path` from then on. Errors from before the first fork
+/// are reported by the original process, which is still attached.
+pub fn daemonize(log_path: &Path, pid_file: &PidFile) -> IoResult<()> {
+    try!(fs::mkdir_recursive(&log_path.dir_path(), io::USER_RWX));
+
+    let log = try!(open_fd(log_path, O_WRONLY | O_CREAT | O_APPEND));
+    let null = try!(open_fd(&Path::new("/dev/null"), O_RDWR));
+
+    try!(fork_and_exit_parent());
+
+    if unsafe { setsid() } < 0 {
+        return Err(IoError::last_error());
+    }
+
+    // The session leader could acquire a terminal by opening one, its child can't
+    try!(fork_and_exit_parent());
+
+    // Stderr may still be seen
+    try!(pid_file.write_pid());
+
+    unsafe {
+        dup2(null, 0);
+        dup2(log, 1);
+        dup2(log, 2);
+        close(null);
+        close(log);
+    }
+
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> IoResult<()> {
+    match unsafe { fork() } {
+        -1 => Err(IoError::last_error()),
+        0 => Ok(()),
+        _ => unsafe { _exit(0) },
+    }
+}
+
+fn open_fd(path: &Path, flags: c_int) -> IoResult<c_int> {
+    let path_c = CString::from_slice(path.as_vec());
+
+    match unsafe { open(path_c.as_ptr(), flags, 0o644) } {
+        -1 => Err(IoError::last_error()),
+        fd => Ok(fd),
+    }
+}
+
+/// Holds an exclusive lock on the pidfile for as long as the daemon runs. The file is removed on drop.
+///
+/// The lock is shared with forked children, so it can be taken before daemonizing.
+pub struct PidFile {
+    path: Path,
+    fd: c_int,
+}
+
+impl PidFile {
+    /// Lock the pidfile. Fails if another daemon holds the lock, or if the pidfile is a symlink.
+    pub fn lock(path: Path) -> IoResult<PidFile> {
+        let fd = try!(open_fd(&path, O_RDWR | O_CREAT | O_CLOEXEC | O_NOFOLLOW));
+
+        if unsafe { flock(fd, LOCK_EX | LOCK_NB) } != 0 {
+            let errno = os::errno();
+            unsafe { close(fd); }
+
+            if errno as i32 != EWOULDBLOCK {
+                return Err(IoError::from_errno(errno, false));
+            }
+
+            let pid = File::open(&path).read_to_string().ok().map_or(String::new(), |pid| pid.trim().to_string());
+
+            return Err(IoError {
+                kind: IoErrorKind::ResourceUnavailable,
+                desc: "A daemon is already running",
+                detail: Some(format!("{} is locked by PID {}", path.display(), pid)),
+            });
+        }
+
+        Ok(PidFile {
+            path: path,
+            fd: fd,
+        })
+    }
+
+    /// Replace the file's contents with our PID.
+    pub fn write_pid(&self) -> IoResult<()> {
+        let pid = format!("{}\n", unsafe { ::libc::getpid() });
+
+        // Through `fd`, reopening the path could follow a symlink put there since
+        if unsafe { ftruncate(self.fd, 0) } != 0 {
+            return Err(IoError::last_error());
+        }
+
+        let written = unsafe { write(self.fd, pid.as_ptr() as *const c_void, pid.len() as size_t) };
+
+        if written < 0 {
+            Err(IoError::last_error())
+        } else if written as usize != pid.len() {
+            Err(IoError {
+                kind: IoErrorKind::ShortWrite(written as usize),
+                desc: "Couldn't write the whole PID",
+                detail: Some(self.path.display().to_string()),
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::unlink(&self.path);
+        unsafe { close(self.fd); }
+    }
+}
@@ -0,0 +1,239 @@
+//! Building the environment of a managed server, and interpolating variables into its command line.
+
+use config::ServerConfig;
+
+use std::collections::HashMap;
+use std::io::{File, IoError, IoErrorKind, IoResult};
+use std::os;
+
+/// The environment a server's process will get, in order: the daemon's own environment unless `clear_env`
+/// is set, then `env_file`, then `INSTANCE` and `PORT` for replicas, then the `env` table.
+/// Later entries override earlier ones.
+pub fn build_env(config: &ServerConfig, instance: Option<u32>) -> IoResult<HashMap<String, String>> {
+    let mut env = HashMap::new();
+
+    if !config.clear_env.unwrap_or(false) {
+        env.extend(os::env().into_iter());
+    }
+
+    if let Some(ref env_file) = config.env_file {
+        let path = Path::new(&*config.dir).join(&**env_file);
+        let contents = try!(File::open(&path).read_to_string());
+
+        let vars = try!(parse_env_file(&*contents).map_err(|msg| env_error(
+            "Invalid env file", format!("{}: {}", path.display(), msg)
+        )));
+
+        env.extend(vars.into_iter());
+    }
+
+    if let Some(instance) = instance {
+        env.insert("INSTANCE".to_string(), instance.to_string());
+
+        if let Some(port_base) = config.port_base {
+            env.insert("PORT".to_string(), (port_base + instance - 1).to_string());
+        }
+    }
+
+    if let Some(ref vars) = config.env {
+        let mut table = Vec::with_capacity(vars.len());
+
+        // Values can refer to inherited variables and those from `env_file`, but not to each other,
+        // since the table has no order
+        for (key, value) in vars.iter() {
+            let value = try!(interpolate(&**value, &env).map_err(|msg| env_error(
+                "Invalid env variable", format!("{}: {}", key, msg)
+            )));
+
+            table.push((key.clone(), value));
+        }
+
+        env.extend(table.into_iter());
+    }
+
+    Ok(env)
+}
+
+/// Replace `${NAME}` and `${NAME:-default}` in the server's command and args with values from `env`.
+pub fn interpolate_command(config: &ServerConfig, env: &HashMap<String, String>) -> IoResult<(String, Vec<String>)> {
+    let interp = |s: &str| interpolate(s, env).map_err(|msg| env_error(
+        "Invalid variable in command", format!("{:?}: {}", s, msg)
+    ));
+
+    let command = try!(interp(&*config.command));
+
+    let mut args = Vec::with_capacity(config.args.len());
+    for arg in config.args.iter() {
+        args.push(try!(interp(&**arg)));
+    }
+
+    Ok((command, args))
+}
+
+/// Parse a dotenv-style file: `KEY=value` lines, with optional `export ` prefixes,
+/// single- or double-quoted values, and `#` comments.
+pub fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+
+    for (num, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with("#") { continue; }
+
+        let line = if line.starts_with("export ") { line[7..].trim_left() } else { line };
+
+        let eq = match line.find('=') {
+            Some(eq) => eq,
+            None => return Err(format!("line {}: expected `KEY=value`", num + 1)),
+        };
+
+        let key = line[..eq].trim();
+        if key.is_empty() {
+            return Err(format!("line {}: missing variable name", num + 1));
+        }
+
+        let value = line[eq + 1..].trim();
+        let value = if value.len() >= 2 && (
+            (value.starts_with("\"") && value.ends_with("\"")) || (value.starts_with("'") && value.ends_with("'"))
+        ) {
+            &value[1..value.len() - 1]
+        } else {
+            value
+        };
+
+        vars.push((key.to_string(), value.to_string()));
+    }
+
+    Ok(vars)
+}
+
+/// Replace `${NAME}` and `${NAME:-default}` with values from `env`. `$$` is a literal `$`.
+/// As in the shell, the default is used if `NAME` is unset or empty.
+pub fn interpolate(s: &str, env: &HashMap<String, String>) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+
+        if rest.starts_with("$") {
+            out.push('$');
+            rest = &rest[1..];
+        } else if rest.starts_with("{") {
+            let close = match rest.find('}') {
+                Some(close) => close,
+                None => return Err("unclosed `${`".to_string()),
+            };
+
+            let var = &rest[1..close];
+            rest = &rest[close + 1..];
+
+            let (name, default) = match var.find_str(":-") {
+                Some(sep) => (&var[..sep], Some(&var[sep + 2..])),
+                None => (var, None),
+            };
+
+            match (env.get(name), default) {
+                (Some(value), Some(default)) if value.is_empty() => out.push_str(default),
+                (Some(value), _) => out.push_str(&**value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => return Err(format!("undefined variable `{}`", name)),
+            }
+        } else {
+            out.push('$');
+        }
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn env_error(desc: &'static str, detail: String) -> IoError {
+    IoError {
+        kind: IoErrorKind::InvalidInput,
+        desc: desc,
+        detail: Some(detail),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{interpolate, parse_env_file};
+
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn variables() {
+        let env = env(&[("HOST", "localhost"), ("PORT", "8080")]);
+
+        assert_eq!(interpolate("${HOST}:${PORT}", &env), Ok("localhost:8080".to_string()));
+        assert_eq!(interpolate("--port=${PORT}", &env), Ok("--port=8080".to_string()));
+        assert_eq!(interpolate("nothing to replace", &env), Ok("nothing to replace".to_string()));
+    }
+
+    #[test]
+    fn defaults() {
+        let env = env(&[("SET", "value"), ("EMPTY", "")]);
+
+        assert_eq!(interpolate("${SET:-default}", &env), Ok("value".to_string()));
+        assert_eq!(interpolate("${UNSET:-default}", &env), Ok("default".to_string()));
+        assert_eq!(interpolate("${EMPTY:-default}", &env), Ok("default".to_string()));
+        assert_eq!(interpolate("${UNSET:-}", &env), Ok("".to_string()));
+        // Without a default, empty is still a value
+        assert_eq!(interpolate("${EMPTY}", &env), Ok("".to_string()));
+    }
+
+    #[test]
+    fn dollars() {
+        let env = env(&[("HOME", "/home/web")]);
+
+        assert_eq!(interpolate("$${HOME}", &env), Ok("${HOME}".to_string()));
+        assert_eq!(interpolate("costs $5", &env), Ok("costs $5".to_string()));
+        assert_eq!(interpolate("$", &env), Ok("$".to_string()));
+    }
+
+    #[test]
+    fn interpolation_errors() {
+        let env = env(&[]);
+
+        assert!(interpolate("${UNSET}", &env).is_err());
+        assert!(interpolate("${UNCLOSED", &env).is_err());
+    }
+
+    #[test]
+    fn env_file() {
+        let contents = "# comment\n\nexport PORT=8080\nNAME = \"my server\"\nGREETING='hi there'\nEMPTY=\nURL=http://host/?a=b\n";
+
+        assert_eq!(parse_env_file(contents), Ok(vars(&[
+            ("PORT", "8080"),
+            ("NAME", "my server"),
+            ("GREETING", "hi there"),
+            ("EMPTY", ""),
+            ("URL", "http://host/?a=b"),
+        ])));
+    }
+
+    #[test]
+    fn unmatched_quotes() {
+        assert_eq!(parse_env_file("A=\"half\nB='\nC=\"mixed'"), Ok(vars(&[
+            ("A", "\"half"),
+            ("B", "'"),
+            ("C", "\"mixed'"),
+        ])));
+    }
+
+    #[test]
+    fn env_file_errors() {
+        assert_eq!(parse_env_file("A=1\nJUST_A_NAME"), Err("line 2: expected `KEY=value`".to_string()));
+        assert_eq!(parse_env_file("=value"), Err("line 1: missing variable name".to_string()));
+    }
+}
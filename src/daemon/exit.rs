@@ -0,0 +1,125 @@
+//! Exit statuses of managed processes, and the history kept for each server.
+
+use util::FormatTime;
+
+use time::{self, Tm};
+
+use std::collections::RingBuf;
+use std::collections::ring_buf;
+use std::fmt;
+use std::io::process::ProcessExit;
+
+/// How many exits are remembered per server
+pub const HISTORY_LEN: usize = 10;
+
+#[derive(Clone)]
+pub struct ExitRecord {
+    pub pid: i32,
+    pub exit: ProcessExit,
+    pub time: Tm,
+    /// How long the process ran, in seconds
+    pub uptime: u64,
+    /// Resource limits the process ran into, e.g. an OOM kill
+    pub limit_hits: Option<String>,
+}
+
+impl ExitRecord {
+    pub fn new(pid: i32, exit: ProcessExit, uptime: u64, limit_hits: Option<String>) -> ExitRecord {
+        ExitRecord {
+            pid: pid,
+            exit: exit,
+            time: time::now(),
+            uptime: uptime,
+            limit_hits: limit_hits,
+        }
+    }
+}
+
+impl fmt::String for ExitRecord {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        try!(fmt.write_fmt(
+            format_args!(
+                "[{}] PID {} {} after {}",
+                self.time.rfc3339(),
+                self.pid,
+                FormatExit(&self.exit),
+                FormatTime::from_s(self.uptime)
+            )
+        ));
+
+        match self.limit_hits {
+            Some(ref hits) => fmt.write_fmt(format_args!(" ({})", hits)),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct FormatExit<'a>(pub &'a ProcessExit);
+
+impl<'a> fmt::String for FormatExit<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self.0 {
+            ProcessExit::ExitStatus(code) => fmt.write_fmt(format_args!("exited with code {}", code)),
+            ProcessExit::ExitSignal(signal) => match signal_name(signal) {
+                Some(name) => fmt.write_fmt(format_args!("killed by signal {} ({})", signal, name)),
+                None => fmt.write_fmt(format_args!("killed by signal {}", signal)),
+            },
+        }
+    }
+}
+
+/// Names for the signals processes commonly die from (Linux numbering).
+pub fn signal_name(signal: isize) -> Option<&'static str> {
+    Some(match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        6 => "SIGABRT",
+        7 => "SIGBUS",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        10 => "SIGUSR1",
+        11 => "SIGSEGV",
+        12 => "SIGUSR2",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        24 => "SIGXCPU",
+        25 => "SIGXFSZ",
+        _ => return None,
+    })
+}
+
+/// The last `HISTORY_LEN` exits of a server, oldest first.
+pub struct ExitHistory {
+    records: RingBuf<ExitRecord>,
+}
+
+impl ExitHistory {
+    pub fn new() -> ExitHistory {
+        ExitHistory {
+            records: RingBuf::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    pub fn push(&mut self, record: ExitRecord) {
+        if self.records.len() == HISTORY_LEN {
+            self.records.pop_front();
+        }
+
+        self.records.push_back(record);
+    }
+
+    pub fn last(&self) -> Option<&ExitRecord> {
+        self.records.back()
+    }
+
+    pub fn iter(&self) -> ring_buf::Iter<ExitRecord> {
+        self.records.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
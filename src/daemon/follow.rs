@@ -0,0 +1,153 @@
+//! Live following of a server's output (`follow`), optionally forwarding input to it (`attach`).
+//!
+//! A follow keeps the client's response open: new lines are streamed under the original request's ID
+//! until the client detaches (a `detach` request, or `~.` in text mode) or the server stops. A client that
+//! stops reading is dropped once a write to it times out, see `CLIENT_WRITE_TIMEOUT`.
+
+use super::{Client, ClientResult, Daemon, fail, finish_response, not_running, usage};
+use super::protocol::{self, DETACH_ESCAPE, ErrorKind, Parsed, Response};
+use super::server::Server;
+
+use std::borrow::ToOwned;
+use std::io::IoResult;
+
+const DEFAULT_BACKLOG: usize = 10;
+
+pub struct Follow {
+    server: String,
+    /// The ID of the `follow`/`attach` request, or `None` in text mode
+    id: Option<u64>,
+    attach: bool,
+    /// Used to notice the instance being replaced by a restart
+    pid: i32,
+    /// How many of the instance's lines have been sent
+    seen: u64,
+}
+
+impl Daemon {
+    pub fn follow_server(&mut self, resp: &mut Response, op: &str, mut args: Vec<String>)
+    -> ClientResult<Option<Follow>> {
+        if args.is_empty() {
+            return usage(resp, &*format!("{} <server> [lines]", op)).map(|_| None);
+        }
+
+        let server = match try!(self.resolve_one(resp, &*args.remove(0))) {
+            Some(server) => server,
+            None => return Ok(None),
+        };
+        let backlog = args.get(0).and_then(|s| s.parse()).unwrap_or(DEFAULT_BACKLOG);
+        let attach = op == "attach";
+
+        let instance = match self.servers.get_mut(&*server) {
+            Some(instance) => instance,
+            None => return not_running(resp, &*server).map(|_| None),
+        };
+
+        let detach = if resp.is_json() { "Close input" } else { "Type `~.` on its own line" };
+
+        if attach {
+            try!(writeln!(resp, "Attached to \"{}\". Lines are sent to the server. {} to detach.", server, detach));
+        } else {
+            try!(writeln!(resp, "Following \"{}\". {} to detach.", server, detach));
+        }
+
+        for line in instance.tail(backlog, None).iter() {
+            try!(writeln!(resp, "{}", line));
+        }
+
+        Ok(Some(Follow {
+            server: server,
+            id: resp.id(),
+            attach: attach,
+            pid: instance.pid(),
+            seen: instance.line_count(),
+        }))
+    }
+
+    /// Handle a line sent by a client that is following a server.
+    pub fn follow_input(&mut self, client: &mut Client, line: &str) {
+        let follow = client.follow.take().unwrap();
+
+        let (detach, input) = if follow.id.is_some() {
+            match protocol::parse_line(line) {
+                Parsed::Json(request) => match &*request.op {
+                    "detach" => (true, None),
+                    "input" => (false, Some(request.args.connect(" "))),
+                    _ => (false, None),
+                },
+                _ => (false, None),
+            }
+        } else {
+            let line = line.trim_right_matches('\n');
+            (line.trim() == DETACH_ESCAPE, Some(line.to_owned()))
+        };
+
+        let mut resp = Response::resume(&mut client.stream, follow.id);
+
+        if detach {
+            let _ = writeln!(resp, "Detached from \"{}\".", follow.server);
+            finish_response(resp);
+            return;
+        }
+
+        if let (true, Some(input)) = (follow.attach, input) {
+            let res = match self.servers.get_mut(&*follow.server) {
+                Some(instance) => instance.send_command(&*input),
+                None => Ok(()),
+            };
+
+            if let Err(err) = res {
+                let _ = writeln!(resp, "Error sending to \"{}\": {}", follow.server, err)
+                    .and_then(|_| resp.flush());
+            }
+        }
+
+        client.follow = Some(follow);
+    }
+
+    /// Send any new lines from the followed server, ending the follow if it has stopped.
+    /// Returns `false` if the lines couldn't be written, and the client should be dropped.
+    pub fn forward_lines(&mut self, client: &mut Client) -> bool {
+        let mut follow = client.follow.take().unwrap();
+        let mut resp = Response::resume(&mut client.stream, follow.id);
+
+        let stopped = match self.servers.get_mut(&*follow.server) {
+            Some(instance) => match send_lines(&mut resp, &mut follow, instance) {
+                Ok(stopped) => stopped,
+                Err(err) => {
+                    println!("Dropping client following \"{}\": {}", follow.server, err);
+                    return false;
+                },
+            },
+            None => true,
+        };
+
+        if stopped {
+            let msg = format!("\"{}\" is no longer running.", follow.server);
+            let _ = fail(&mut resp, ErrorKind::NotRunning, msg);
+            finish_response(resp);
+        } else {
+            client.follow = Some(follow);
+        }
+
+        true
+    }
+}
+
+/// Write the instance's new lines and flush them. Returns whether the instance has stopped for good.
+fn send_lines(resp: &mut Response, follow: &mut Follow, instance: &mut Server) -> IoResult<bool> {
+    if instance.pid() != follow.pid {
+        try!(writeln!(resp, "--- \"{}\" restarted ---", follow.server));
+        follow.pid = instance.pid();
+        follow.seen = 0;
+    }
+
+    for line in instance.lines_since(follow.seen).iter() {
+        try!(writeln!(resp, "{}", line));
+    }
+
+    follow.seen = instance.line_count();
+    try!(resp.flush());
+
+    Ok(!instance.is_alive() && !instance.may_restart())
+}
@@ -0,0 +1,188 @@
+//! Running commands on behalf of a server: lifecycle hooks and scheduled hooks.
+//!
+//! Hooks run in the server's directory as the server's user, group and umask, with the server's environment
+//! plus variables describing the event: `SHEPHERD_SERVER`, `SHEPHERD_EVENT`, and where known `SHEPHERD_PID`
+//! and `SHEPHERD_EXIT_CODE` or `SHEPHERD_EXIT_SIGNAL`. Their output goes to the daemon's own output.
+//!
+//! `pre_start` and `pre_stop` run on the daemon's main loop, which waits for them: until they exit or
+//! their timeout passes, no other client, restart, health check or schedule is served. So their timeout is
+//! capped at `BLOCKING_TIMEOUT`. The other hooks run in the background.
+
+use config::{HookConfig, ServerConfig, split_instance};
+use super::env::build_env;
+use super::exit::FormatExit;
+use super::shim::{ShimOptions, check_exec};
+use super::supervise::track_child;
+
+use std::cmp::min;
+
+use std::io::{IoError, IoErrorKind, IoResult};
+use std::io::process::{Command, ProcessExit, StdioContainer};
+use std::thread::Thread;
+
+/// Default time a hook may run for, in seconds
+pub const DEFAULT_TIMEOUT: u64 = 60;
+/// The default, and longest, time `pre_start` and `pre_stop` may hold up the daemon, in seconds
+pub const BLOCKING_TIMEOUT: u64 = 10;
+
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum Event {
+    /// Before the server's process is started. A failing hook stops the start.
+    PreStart,
+    /// After the server's process has started
+    PostStart,
+    /// Before the server is asked to stop
+    PreStop,
+    /// After the server has stopped
+    PostStop,
+    /// When the server exits unsuccessfully without being stopped
+    Crash,
+    /// Run by a schedule
+    Schedule,
+}
+
+impl Event {
+    /// Whether the daemon waits for the hook, see `BLOCKING_TIMEOUT`.
+    fn blocks(&self) -> bool {
+        *self == Event::PreStart || *self == Event::PreStop
+    }
+
+    /// As used in the config and `SHEPHERD_EVENT`
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::PreStart => "pre_start",
+            Event::PostStart => "post_start",
+            Event::PreStop => "pre_stop",
+            Event::PostStop => "post_stop",
+            Event::Crash => "on_crash",
+            Event::Schedule => "schedule",
+        }
+    }
+
+    fn hook(&self, config: &ServerConfig) -> Option<HookConfig> {
+        let hooks = match config.hooks {
+            Some(ref hooks) => hooks,
+            None => return None,
+        };
+
+        match *self {
+            Event::PreStart => hooks.pre_start.clone(),
+            Event::PostStart => hooks.post_start.clone(),
+            Event::PreStop => hooks.pre_stop.clone(),
+            Event::PostStop => hooks.post_stop.clone(),
+            Event::Crash => hooks.on_crash.clone(),
+            Event::Schedule => None,
+        }
+    }
+}
+
+/// Run the server's hook for `event`, if it has one, and wait for it, blocking the caller for up to the
+/// hook's timeout, or `BLOCKING_TIMEOUT` for `pre_start` and `pre_stop`. Fails if the hook couldn't run, timed out or exited unsuccessfully.
+pub fn run_event(event: Event, server: &str, config: &ServerConfig, pid: Option<i32>, exit: Option<&ProcessExit>)
+-> IoResult<()> {
+    let hook = match event.hook(config) {
+        Some(hook) => hook,
+        None => return Ok(()),
+    };
+
+    println!("Running {} hook of \"{}\"...", event.name(), server);
+
+    let timeout = if event.blocks() {
+        min(hook.timeout.unwrap_or(BLOCKING_TIMEOUT), BLOCKING_TIMEOUT)
+    } else {
+        hook.timeout.unwrap_or(DEFAULT_TIMEOUT)
+    };
+    let exit = try!(run_hook(server, config, &*hook.command, timeout, event_vars(event, pid, exit)));
+
+    if exit.success() {
+        Ok(())
+    } else {
+        Err(IoError {
+            kind: IoErrorKind::OtherIoError,
+            desc: "Hook failed",
+            detail: Some(format!("{} hook {:?} {}", event.name(), hook.command, FormatExit(&exit))),
+        })
+    }
+}
+
+/// Run the server's hook for `event` in the background, if it has one, logging the result.
+pub fn spawn_event(event: Event, server: &str, config: &ServerConfig, pid: Option<i32>, exit: Option<&ProcessExit>) {
+    if event.hook(config).is_none() {
+        return;
+    }
+
+    let server = server.to_string();
+    let config = config.clone();
+    let exit = exit.map(|exit| exit.clone());
+
+    Thread::spawn(move || {
+        if let Err(err) = run_event(event, &*server, &config, pid, exit.as_ref()) {
+            println!("Error running {} hook of \"{}\": {}", event.name(), server, err);
+        }
+    });
+}
+
+fn event_vars(event: Event, pid: Option<i32>, exit: Option<&ProcessExit>) -> Vec<(String, String)> {
+    let mut vars = vec![("SHEPHERD_EVENT".to_string(), event.name().to_string())];
+
+    if let Some(pid) = pid {
+        vars.push(("SHEPHERD_PID".to_string(), pid.to_string()));
+    }
+
+    match exit {
+        Some(&ProcessExit::ExitStatus(code)) => vars.push(("SHEPHERD_EXIT_CODE".to_string(), code.to_string())),
+        Some(&ProcessExit::ExitSignal(signal)) => vars.push(("SHEPHERD_EXIT_SIGNAL".to_string(), signal.to_string())),
+        None => (),
+    }
+
+    vars
+}
+
+/// Run `command` for `server` as its user and wait for it, killing it after `timeout` seconds.
+pub fn run_hook(server: &str, config: &ServerConfig, command: &[String], timeout: u64, vars: Vec<(String, String)>)
+-> IoResult<ProcessExit> {
+    let mut env: Vec<(String, String)> = try!(build_env(config, split_instance(server).1)).into_iter().collect();
+    env.push(("SHEPHERD_SERVER".to_string(), server.to_string()));
+    env.extend(vars.into_iter());
+
+    let shim = try!(ShimOptions::for_hooks(config));
+
+    let mut hook = match shim {
+        Some(ref shim) => try!(shim.command(&*command[0], &command[1..])),
+        None => {
+            let mut hook = Command::new(&*command[0]);
+            hook.args(&command[1..]);
+            hook
+        },
+    };
+
+    hook.cwd(&Path::new(&*config.dir))
+        .env_set_all(&*env)
+        .stdin(StdioContainer::Ignored)
+        .stdout(StdioContainer::InheritFd(1))
+        .stderr(StdioContainer::InheritFd(2));
+
+    let mut process = try!(hook.spawn());
+    let _tracked = track_child(process.id());
+
+    if shim.is_some() {
+        try!(check_exec(&mut process));
+    }
+
+    process.set_timeout(Some(timeout * 1000));
+
+    match process.wait() {
+        Ok(exit) => Ok(exit),
+        Err(_) => {
+            let _ = process.signal_kill();
+            process.set_timeout(None);
+            let _ = process.wait();
+
+            Err(IoError {
+                kind: IoErrorKind::TimedOut,
+                desc: "Hook timed out",
+                detail: Some(format!("{:?} ran for more than {}s", command, timeout)),
+            })
+        },
+    }
+}
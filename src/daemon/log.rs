@@ -0,0 +1,226 @@
+//! Persistent log files for managed servers, with size- and age-based rotation.
+//!
+//! Rotated files are named `<log>.1`, `<log>.2`, ..., newest first, and optionally gzipped
+//! (`<log>.1.gz`) in the background. Only `retain` rotated files are kept. A log's age is taken from the
+//! timestamp on its first line, so it carries over when the server is restarted.
+
+use config::ServerConfig;
+use super::supervise::track_child;
+
+use time;
+
+use std::io::{self, BufferedReader, File, FileAccess, FileMode, IoResult};
+use std::io::fs::{self, PathExtensions};
+use std::io::process::Command;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread::Thread;
+
+pub const DEFAULT_MAX_SIZE: u64 = 10_000_000;
+pub const DEFAULT_RETAIN: usize = 5;
+
+#[derive(Clone)]
+pub struct LogSettings {
+    pub path: String,
+    /// Rotate once the file grows past this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotate once this many seconds have passed since the file's first line was written.
+    pub max_age: Option<u64>,
+    pub retain: usize,
+    pub compress: bool,
+}
+
+impl LogSettings {
+    pub fn for_config(config: &ServerConfig) -> Option<LogSettings> {
+        config.log_file.as_ref().map(|path| LogSettings {
+            path: path.clone(),
+            max_size: Some(config.log_rotate_size.unwrap_or(DEFAULT_MAX_SIZE)),
+            max_age: config.log_rotate_interval,
+            retain: config.log_retain.unwrap_or(DEFAULT_RETAIN),
+            compress: config.log_compress.unwrap_or(true),
+        })
+    }
+}
+
+pub struct LogFile {
+    settings: LogSettings,
+    file: File,
+    size: u64,
+    started_at: i64,
+    /// Sent to once the last rotated file has been compressed
+    compressing: Option<Receiver<()>>,
+}
+
+impl LogFile {
+    pub fn open(settings: LogSettings) -> IoResult<LogFile> {
+        let path = Path::new(&*settings.path);
+        try!(fs::mkdir_recursive(&path.dir_path(), io::USER_RWX));
+
+        let file = try!(open_append(&path));
+        let size = try!(fs::stat(&path)).size;
+
+        Ok(LogFile {
+            settings: settings,
+            file: file,
+            size: size,
+            started_at: started_at(&path),
+            compressing: None,
+        })
+    }
+
+    /// Append a line, prefixed with the current time, rotating first if necessary.
+    pub fn write_line(&mut self, line: &str) -> IoResult<()> {
+        if self.should_rotate() && !self.still_compressing() {
+            try!(self.rotate());
+        }
+
+        let line = format!("[{}] {}\n", time::now().rfc3339(), line.trim_right());
+        try!(self.file.write_str(&*line).and_then(|_| self.file.flush()));
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Open the file again, e.g. because something else moved it away.
+    pub fn reopen(&mut self) -> IoResult<()> {
+        let path = Path::new(&*self.settings.path);
+        try!(fs::mkdir_recursive(&path.dir_path(), io::USER_RWX));
+
+        self.file = try!(open_append(&path));
+        self.size = try!(fs::stat(&path)).size;
+        self.started_at = started_at(&path);
+
+        Ok(())
+    }
+
+    fn should_rotate(&self) -> bool {
+        let too_big = self.settings.max_size.map_or(false, |max| self.size >= max);
+        let too_old = self.settings.max_age.map_or(false, |max| now_s() - self.started_at >= max as i64);
+
+        self.size > 0 && (too_big || too_old)
+    }
+
+    /// Whether gzip is still working on the last rotated file, which mustn't be moved until it's done.
+    /// Rotation waits for the next line rather than holding up the server's output.
+    fn still_compressing(&mut self) -> bool {
+        let busy = match self.compressing {
+            Some(ref done) => done.try_recv() == Err(TryRecvError::Empty),
+            None => false,
+        };
+
+        if !busy {
+            self.compressing = None;
+        }
+
+        busy
+    }
+
+    fn rotate(&mut self) -> IoResult<()> {
+        let retain = self.settings.retain;
+        let current = Path::new(&*self.settings.path);
+
+        // Drop the oldest file, then shift the rest up by one
+        for &compressed in [false, true].iter() {
+            let oldest = self.rotated_path(retain, compressed);
+            if oldest.exists() {
+                try!(fs::unlink(&oldest));
+            }
+        }
+
+        for n in (1..retain).rev() {
+            for &compressed in [false, true].iter() {
+                let from = self.rotated_path(n, compressed);
+                if from.exists() {
+                    try!(fs::rename(&from, &self.rotated_path(n + 1, compressed)));
+                }
+            }
+        }
+
+        if retain > 0 {
+            try!(fs::rename(&current, &self.rotated_path(1, false)));
+        } else {
+            try!(fs::unlink(&current));
+        }
+
+        self.file = try!(open_append(&current));
+        self.size = 0;
+        self.started_at = now_s();
+
+        if retain > 0 && self.settings.compress {
+            self.compressing = Some(compress(self.rotated_path(1, false)));
+        }
+
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize, compressed: bool) -> Path {
+        Path::new(format!("{}.{}{}", self.settings.path, n, if compressed { ".gz" } else { "" }))
+    }
+}
+
+fn open_append(path: &Path) -> IoResult<File> {
+    File::open_mode(path, FileMode::Append, FileAccess::Write)
+}
+
+/// Gzip `path` on another thread, so output isn't held up. The receiver is sent to when it's done.
+fn compress(path: Path) -> Receiver<()> {
+    let (done, rx) = channel();
+
+    Thread::spawn(move || {
+        let status = Command::new("gzip").arg("-f").arg(&path).spawn().and_then(|mut process| {
+            let _tracked = track_child(process.id());
+            process.wait()
+        });
+
+        match status {
+            Ok(status) if status.success() => (),
+            Ok(status) => println!("Failed to compress \"{}\": gzip {}", path.display(), status),
+            Err(err) => println!("Failed to compress \"{}\": {}", path.display(), err),
+        }
+
+        let _ = done.send(());
+    });
+
+    rx
+}
+
+/// When the log at `path` was started, from the timestamp `write_line` put on its first line.
+/// A new log, or one whose first line has no timestamp, counts as started now.
+fn started_at(path: &Path) -> i64 {
+    let first_line = File::open(path).and_then(|file| BufferedReader::new(file).read_line());
+
+    first_line.ok()
+        .and_then(|line| match line.find(']') {
+            Some(end) if line.starts_with("[") => parse_timestamp(&line[1..end]),
+            _ => None,
+        })
+        .unwrap_or_else(now_s)
+}
+
+/// Parse a timestamp as written by `Tm::rfc3339`, e.g. `2015-01-01T12:00:00+01:00`, into seconds since the epoch.
+fn parse_timestamp(stamp: &str) -> Option<i64> {
+    if stamp.len() < 20 || !stamp.bytes().all(|byte| byte < 128) {
+        return None;
+    }
+
+    let tm = match time::strptime(&stamp[..19], "%Y-%m-%dT%H:%M:%S") {
+        Ok(tm) => tm,
+        Err(_) => return None,
+    };
+
+    let offset = match &stamp[19..] {
+        "Z" => 0,
+        offset if offset.len() == 6 => match (&offset[..1], offset[1..3].parse::<i64>(), offset[4..].parse::<i64>()) {
+            ("+", Some(hours), Some(minutes)) => hours * 3600 + minutes * 60,
+            ("-", Some(hours), Some(minutes)) => -(hours * 3600 + minutes * 60),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    // Read as UTC, since the parsed time has no offset
+    Some(tm.to_timespec().sec - offset)
+}
+
+fn now_s() -> i64 {
+    time::get_time().sec
+}
@@ -0,0 +1,270 @@
+//! An optional HTTP endpoint serving server metrics in the OpenMetrics text format, for Prometheus.
+//!
+//! Scrapes are answered by a thread of their own, so a slow scraper can't hold up the daemon. The main loop
+//! renders the metrics every `RENDER_INTERVAL` and publishes them for that thread to serve.
+
+use config::MetricsListen;
+use super::Daemon;
+use util::precise_time_ms;
+
+use std::io::{self, Acceptor, BufferedStream, FileType, IoError, IoErrorKind, IoResult, Listener};
+use std::io::net::pipe::{UnixListener, UnixStream};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::fs;
+use std::io::process::ProcessExit;
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+
+const CONTENT_TYPE: &'static str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// How long a scraper gets to send its request and read the response, in ms
+const REQUEST_TIMEOUT: u64 = 1000;
+/// How often the published metrics are rendered again, in ms
+const RENDER_INTERVAL: u64 = 1000;
+
+pub struct MetricsListener {
+    /// The latest rendering, served to every scrape until the next one
+    body: Arc<Mutex<String>>,
+    rendered_at: u64,
+}
+
+impl MetricsListener {
+    /// Bind and start serving scrapes in the background.
+    pub fn bind(listen: &MetricsListen) -> IoResult<MetricsListener> {
+        let body = Arc::new(Mutex::new(String::new()));
+        let served = body.clone();
+
+        match *listen {
+            MetricsListen::Tcp(ref addr) => {
+                let acceptor = try!(try!(TcpListener::bind(&**addr)).listen());
+                Thread::spawn(move || serve(acceptor, served));
+            },
+            MetricsListen::Unix(ref path) => {
+                let path = Path::new(&**path);
+                try!(remove_stale_socket(&path));
+
+                let acceptor = try!(try!(UnixListener::bind(&path)).listen());
+                Thread::spawn(move || serve(acceptor, served));
+            },
+        }
+
+        Ok(MetricsListener {
+            body: body,
+            rendered_at: 0,
+        })
+    }
+}
+
+/// Clear the way for binding a Unix socket at `path`, refusing if it's anything but a socket nobody listens on.
+fn remove_stale_socket(path: &Path) -> IoResult<()> {
+    let stat = match fs::lstat(path) {
+        Ok(stat) => stat,
+        Err(ref err) if err.kind == IoErrorKind::FileNotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    // There's no file type for sockets, they come out as `Unknown`
+    if stat.kind != FileType::Unknown {
+        return Err(IoError {
+            kind: IoErrorKind::PathAlreadyExists,
+            desc: "The metrics socket path is taken by something other than a socket",
+            detail: Some(path.display().to_string()),
+        });
+    }
+
+    if UnixStream::connect(path).is_ok() {
+        return Err(IoError {
+            kind: IoErrorKind::ResourceUnavailable,
+            desc: "Something is already listening on the metrics socket",
+            detail: Some(path.display().to_string()),
+        });
+    }
+
+    // Left over from a previous daemon
+    fs::unlink(path)
+}
+
+/// The streams scrapes arrive on
+trait ScrapeStream: io::Stream {
+    /// Fail reads and writes after `timeout` ms
+    fn set_deadline(&mut self, timeout: u64);
+}
+
+impl ScrapeStream for TcpStream {
+    fn set_deadline(&mut self, timeout: u64) {
+        self.set_timeout(Some(timeout));
+    }
+}
+
+impl ScrapeStream for UnixStream {
+    fn set_deadline(&mut self, timeout: u64) {
+        self.set_timeout(Some(timeout));
+    }
+}
+
+/// Answer scrapes one at a time until the daemon exits.
+fn serve<A, S>(mut acceptor: A, body: Arc<Mutex<String>>) where A: Acceptor<S>, S: ScrapeStream {
+    loop {
+        match acceptor.accept() {
+            Ok(stream) => if let Err(err) = answer_scrape(stream, &*body) {
+                println!("Error serving metrics: {}", err);
+            },
+            Err(err) => println!("Error accepting metrics connection: {}", err),
+        }
+    }
+}
+
+fn answer_scrape<S: ScrapeStream>(stream: S, body: &Mutex<String>) -> IoResult<()> {
+    // However slowly the request trickles in, it has to be done by then
+    let deadline = precise_time_ms() + REQUEST_TIMEOUT;
+    let mut stream = BufferedStream::new(stream);
+
+    let request = try!(read_line_by(&mut stream, deadline));
+
+    // Skip the headers, we don't need any of them
+    loop {
+        let header = try!(read_line_by(&mut stream, deadline));
+        if header.trim().is_empty() { break; }
+    }
+
+    let mut words = request.words();
+    let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", CONTENT_TYPE, body.lock().unwrap().clone()),
+        ("GET", _) => ("404 Not Found", "text/plain", "Metrics are served at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Only GET is supported\n".to_string()),
+    };
+
+    try!(write!(
+        &mut stream,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    ));
+
+    stream.flush()
+}
+
+fn read_line_by<S: ScrapeStream>(stream: &mut BufferedStream<S>, deadline: u64) -> IoResult<String> {
+    let now = precise_time_ms();
+
+    if now >= deadline {
+        return Err(IoError {
+            kind: IoErrorKind::TimedOut,
+            desc: "Scrape request took too long",
+            detail: None,
+        });
+    }
+
+    stream.get_mut().set_deadline(deadline - now);
+    stream.read_line()
+}
+
+impl Daemon {
+    /// Render the metrics for the scrape thread, if it's time to.
+    pub fn publish_metrics(&mut self) {
+        let now = precise_time_ms();
+
+        if self.metrics.as_ref().map_or(true, |metrics| now - metrics.rendered_at < RENDER_INTERVAL) {
+            return;
+        }
+
+        let rendered = self.render_metrics();
+        let metrics = self.metrics.as_mut().unwrap();
+
+        *metrics.body.lock().unwrap() = rendered;
+        metrics.rendered_at = now;
+    }
+
+    fn render_metrics(&mut self) -> String {
+        // Replicas are reported separately, along with instances whose config was removed by a reload
+        let mut names: Vec<String> = self.config.servers.keys()
+            .flat_map(|server| self.instance_names(&**server).into_iter())
+            .collect();
+
+        let others: Vec<String> = self.servers.keys().filter(|name| !names.contains(*name)).cloned().collect();
+        names.extend(others.into_iter());
+        names.sort();
+
+        let mut up = Vec::new();
+        let mut restarts = Vec::new();
+        let mut uptime = Vec::new();
+        let mut cpu = Vec::new();
+        let mut memory = Vec::new();
+        let mut exit_code = Vec::new();
+        let mut exit_signal = Vec::new();
+
+        let processes = match self.process_table() {
+            Ok(processes) => Some(processes),
+            Err(err) => {
+                println!("Error reading processes for metrics: {}", err);
+                None
+            },
+        };
+
+        for name in names.iter() {
+            let label = format!("server=\"{}\"", escape_label(&**name));
+
+            let instance = match self.servers.get_mut(name) {
+                Some(instance) => instance,
+                None => {
+                    up.push((label, 0.0));
+                    continue;
+                },
+            };
+
+            let alive = instance.is_alive();
+            up.push((label.clone(), if alive { 1.0 } else { 0.0 }));
+            restarts.push((label.clone(), instance.restart_count() as f64));
+
+            if let (true, &Some(ref processes)) = (alive, &processes) {
+                if let Ok(info) = instance.info(&**processes) {
+                    uptime.push((label.clone(), info.uptime as f64));
+                    cpu.push((label.clone(), info.percent_cpu as f64));
+                    memory.push((label.clone(), info.memory_usage as f64));
+                }
+            }
+
+            match instance.last_exit().map(|record| &record.exit) {
+                Some(&ProcessExit::ExitStatus(code)) => exit_code.push((label, code as f64)),
+                Some(&ProcessExit::ExitSignal(signal)) => exit_signal.push((label, signal as f64)),
+                None => (),
+            }
+        }
+
+        let mut out = String::new();
+
+        write_family(&mut out, "shepherd_server_up", "gauge", "Whether the server's process is running", "", &*up);
+        write_family(
+            &mut out, "shepherd_server_restarts", "counter", "Automatic restarts of the server", "_total", &*restarts
+        );
+        write_family(
+            &mut out, "shepherd_server_uptime_seconds", "gauge", "How long the server's process has run", "", &*uptime
+        );
+        write_family(
+            &mut out, "shepherd_server_cpu_percent", "gauge", "Average CPU usage over the process's lifetime", "", &*cpu
+        );
+        write_family(&mut out, "shepherd_server_memory_bytes", "gauge", "Resident memory of the process", "", &*memory);
+        write_family(
+            &mut out, "shepherd_server_last_exit_code", "gauge", "Exit code of the server's last process", "", &*exit_code
+        );
+        write_family(
+            &mut out, "shepherd_server_last_exit_signal", "gauge", "Signal that killed the server's last process", "",
+            &*exit_signal
+        );
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+fn write_family(out: &mut String, name: &str, kind: &str, help: &str, suffix: &str, samples: &[(String, f64)]) {
+    out.push_str(&*format!("# TYPE {} {}\n# HELP {} {}\n", name, kind, name, help));
+
+    for &(ref labels, value) in samples.iter() {
+        out.push_str(&*format!("{}{}{{{}}} {}\n", name, suffix, labels, value));
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
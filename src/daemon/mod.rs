@@ -1,30 +1,77 @@
-use config::Config;
-use util::ignore_timeout;
-
-pub use self::remote::RemoteDaemon;
-
-use self::server::Server;
-
-use std::borrow::ToOwned;
-use std::collections::hash_map::{Entry, HashMap};
+use config::{self, Config, ServerConfig, split_instance};
+use util::{ignore_timeout, precise_time_ms};
+
+pub use self::daemonize::{claim, daemonize, PidFile};
+pub use self::remote::{RemoteDaemon, StdinLines};
+pub use self::supervise::run_foreground;
+
+use self::exit::FormatExit;
+use self::follow::Follow;
+use self::metrics::MetricsListener;
+use self::probe::Readiness;
+use self::protocol::{ErrorKind, Parsed, PROTOCOL_VERSION, Response};
+use self::restart::RestartState;
+use self::schedule::Scheduler;
+use self::server::{Server, Stream};
+use self::signals::Signals;
+use self::stats::Sample;
+use self::supervise::Supervisor;
+use self::tree::{ProcessEntry, ProcessTable};
+
+use std::collections::HashMap;
 use std::error::FromError;
-use std::io::{Acceptor, BufferedStream, IoError, Listener};
+use std::io::{Acceptor, BufferedStream, IoError, IoResult, Listener, stdio};
 use std::io::fs::{self, PathExtensions};
 use std::io::net::pipe::{UnixAcceptor, UnixListener, UnixStream};
-
+use std::mem;
+use std::rc::Rc;
+
+pub mod protocol;
+pub mod shim;
+
+mod cgroup;
+mod daemonize;
+mod env;
+mod exit;
+mod follow;
+mod hooks;
+mod log;
+mod metrics;
+mod probe;
+mod reload;
 mod remote;
+mod replica;
+mod restart;
+mod schedule;
 mod server;
+mod signals;
+mod stats;
+mod supervise;
+mod tree;
 
 pub const TIMEOUT: Option<u64> = Some(100);
+/// How long to wait for a line from each client per tick
+pub const CLIENT_TIMEOUT: Option<u64> = Some(10);
+/// How long writes to each client may take per tick. A follower that stops reading is dropped
+/// rather than blocking the daemon.
+const CLIENT_WRITE_TIMEOUT: Option<u64> = Some(1000);
+
+/// Run the daemon in the background, see `daemonize`. `pid_file` is the one claimed for `config`.
+pub fn start(config: Config, pid_file: PidFile) {
+    run(config, None, pid_file)
+}
 
-pub fn start(config: Config) {
-    println!("Starting daemon... Type Ctrl-z to stop and `bg` to run in backround");
+/// `pid_file`, from `claim()`, is held until the daemon exits.
+fn run(config: Config, supervisor: Option<Supervisor>, _pid_file: PidFile) {
+    println!("Starting daemon (PID {})...", unsafe { ::libc::getpid() });
 
     let ref socket_path = Path::new(&*config.socket_path);
     
+    // `claim()` made sure nothing is listening on it
     if socket_path.exists() {
+        println!("Removing stale socket {}", socket_path.display());
         fs::unlink(socket_path).ok()
-            .expect("Socket already exists and cannot be deleted! Is a daemon already running?");   
+            .expect("Socket is stale but cannot be deleted!");   
     } 
 
     let listener = UnixListener::bind(socket_path).unwrap();
@@ -33,23 +80,55 @@ pub fn start(config: Config) {
     // 100ms timeout
     acceptor.set_timeout(TIMEOUT);
 
-    let mut daemon = Daemon::new(config, acceptor);
-    let mut clients: Vec<ClientStream> = Vec::new();
+    let metrics = config.metrics.as_ref().and_then(|listen| match MetricsListener::bind(listen) {
+        Ok(listener) => {
+            println!("Serving metrics on {:?}", listen);
+            Some(listener)
+        },
+        Err(err) => {
+            println!("Couldn't serve metrics on {:?}: {}", listen, err);
+            None
+        },
+    });
+
+    let mut signals = Signals::install().ok().expect("Couldn't install signal handlers!");
+
+    // Before any server starts, so the daemon's cgroup has no other processes when controllers are enabled
+    let needs_cgroup = config.servers.values()
+        .any(|server| server.limits.as_ref().map_or(false, |limits| limits.needs_cgroup()));
+
+    if needs_cgroup {
+        if let Err(err) = cgroup::prepare() {
+            println!("Cgroup limits won't be applied: {}", err);
+        }
+    }
+
+    let mut daemon = Daemon::new(config, acceptor, metrics, supervisor);
+    let mut clients: Vec<Client> = Vec::new();
 
     daemon.start_servers();
+    daemon.notify("READY=1");
 
-    while daemon.manage_clients(&mut clients) {
+    while daemon.manage_clients(&mut clients) && daemon.handle_signals(&mut signals) {
         daemon.check_instances();
+        daemon.start_waiting();
+        daemon.supervise();
+        daemon.publish_metrics();
+        daemon.run_scheduled();
+
+        // Rescanned the next time it's needed
+        daemon.processes = None;
     }
 
     for mut client in clients.into_iter() {
-        let client = client.get_mut();
+        let client = client.stream.get_mut();
         if let Err(err) = client.close_write().and_then(|_| client.close_read()) {
             println!("Error closing client: {}", err);    
         }
     }
 
     daemon.acceptor.close_accept().unwrap();
+    let _ = fs::unlink(socket_path);
 }
 
 pub type ClientStream = BufferedStream<UnixStream>;
@@ -58,94 +137,438 @@ pub fn buffered(stream: UnixStream) -> ClientStream {
     BufferedStream::new(stream)    
 }
 
+struct Client {
+    stream: ClientStream,
+    /// Set while the client is following a server's output
+    follow: Option<Follow>,
+}
+
+impl Client {
+    fn new(stream: UnixStream) -> Client {
+        Client {
+            stream: buffered(stream),
+            follow: None,
+        }
+    }
+}
+
+/// Instances of a server whose start waits for what it depends on or starts after to become ready
+struct WaitingStart {
+    server: String,
+    instances: Vec<String>,
+}
+
 struct Daemon {
     config: Config,
     acceptor: UnixAcceptor,
+    metrics: Option<MetricsListener>,
+    scheduler: Scheduler,
+    /// Replica counts set by `scale`, overriding `instances`
+    scales: HashMap<String, u32>,
     servers: HashMap<String, Server>,
+    /// Started by `start_waiting` once they can be, in the order they were put aside
+    waiting: Vec<WaitingStart>,
+    /// Set when running in the foreground
+    supervisor: Option<Supervisor>,
+    /// This tick's snapshot of `/proc`, see `process_table`
+    processes: Option<Rc<ProcessTable>>,
 }
 
 impl Daemon {
-    fn new(config: Config, acceptor: UnixAcceptor) -> Daemon {
+    fn new(config: Config, acceptor: UnixAcceptor, metrics: Option<MetricsListener>, supervisor: Option<Supervisor>)
+    -> Daemon {
         Daemon {
+            scheduler: Scheduler::new(&config),
             config: config,
             acceptor: acceptor,
+            metrics: metrics,
+            scales: HashMap::new(),
             servers: HashMap::new(),
+            waiting: Vec::new(),
+            supervisor: supervisor,
+            processes: None,
         }    
     }
 
+    /// The processes on the system, scanned at most once per tick.
+    fn process_table(&mut self) -> IoResult<Rc<ProcessTable>> {
+        if self.processes.is_none() {
+            self.processes = Some(Rc::new(try!(ProcessTable::scan())));
+        }
+
+        Ok(self.processes.clone().unwrap())
+    }
+
     fn start_servers(&mut self) {
-        for server in self.config.start_servers.iter().cloned() {
-            if let Some(config) = self.config.servers.get(&*server).cloned() {
-                println!("Auto-starting \"{}\"...", server);
-                match Server::spawn(config) {
+        let start_servers = self.config.start_servers.clone();
+        println!("Auto-starting {:?}...", start_servers);
+
+        let mut stdout = stdio::stdout();
+
+        match self.start_all(&*start_servers, &mut stdout) {
+            Ok(ref failed) if failed.is_empty() => (),
+            Ok(failed) => println!("Failed to auto-start {:?}", failed),
+            Err(err) => println!("Error auto-starting servers: {}", err),
+        }
+    }
+
+    /// Start `servers` and anything they depend on, in dependency order, skipping those already running.
+    /// A replicated server is started as all of its replicas, unless only some instances are named.
+    ///
+    /// A server whose dependencies or `after` servers are still starting up is put aside, and started by
+    /// `start_waiting` once they're ready, so the daemon isn't held up meanwhile. Nothing waits for the
+    /// servers started here to become ready.
+    ///
+    /// Returns the instances that failed to start.
+    fn start_all(&mut self, servers: &[String], w: &mut Writer) -> IoResult<Vec<String>> {
+        let mut failed = Vec::new();
+
+        let mut bases: Vec<String> = Vec::new();
+        for name in servers.iter() {
+            let base = split_instance(&**name).0.to_string();
+            if !bases.contains(&base) { bases.push(base); }
+        }
+
+        'servers: for server in self.config.start_order(&*bases).into_iter() {
+            let config = match self.config.servers.get(&server) {
+                Some(config) => config.clone(),
+                None => {
+                    try!(writeln!(w, "No configuration for \"{}\"", server));
+                    failed.push(server);
+                    continue;
+                }
+            };
+
+            let mut named: Vec<String> = servers.iter()
+                .filter(|name| **name != server && split_instance(&***name).0 == &*server)
+                .cloned()
+                .collect();
+
+            let beyond: Vec<String> = named.iter().filter(|name| !self.in_scale(&***name)).cloned().collect();
+            if !beyond.is_empty() {
+                try!(writeln!(w, "Not starting {:?}: beyond the instances of \"{}\"", beyond, server));
+                named.retain(|name| !beyond.contains(name));
+                failed.extend(beyond.into_iter());
+
+                if named.is_empty() && !servers.contains(&server) { continue; }
+            }
+
+            let instances = if named.is_empty() || servers.contains(&server) {
+                self.instance_names(&*server)
+            } else {
+                named
+            };
+
+            let instances: Vec<String> = instances.into_iter().filter(|name| !self.is_running(&**name)).collect();
+            if instances.is_empty() { continue; }
+
+            if self.waiting.iter().any(|start| start.server == server) {
+                try!(writeln!(w, "\"{}\" is already waiting to start", server));
+                continue;
+            }
+
+            let unready = self.unready(&config);
+            if !unready.is_empty() {
+                try!(writeln!(w, "\"{}\" will start once {:?} are ready", server, unready));
+                self.waiting.push(WaitingStart { server: server, instances: instances });
+                continue;
+            }
+
+            for dep in config.dependencies().iter() {
+                if !self.is_up(&**dep) {
+                    try!(writeln!(w, "Not starting \"{}\": dependency \"{}\" is not running", server, dep));
+                    failed.extend(instances.into_iter());
+                    continue 'servers;
+                }
+
+                for dep_instance in self.instances_of(&**dep).into_iter() {
+                    let not_ready = self.servers.get(&dep_instance).unwrap().readiness() == Readiness::NotReady;
+
+                    if not_ready && self.is_running(&*dep_instance) {
+                        try!(writeln!(w, "Not starting \"{}\": dependency \"{}\" is not ready", server, dep_instance));
+                        failed.extend(instances.into_iter());
+                        continue 'servers;
+                    }
+                }
+            }
+
+            for name in instances.into_iter() {
+                let config = match self.config.instance_config(&*name) {
+                    Some(config) => config,
+                    None => {
+                        try!(writeln!(w, "No configuration for \"{}\"", name));
+                        failed.push(name);
+                        continue;
+                    }
+                };
+
+                try!(writeln!(w, "Starting \"{}\"...", name).and_then(|_| w.flush()));
+
+                // Reuse a lingering instance so its history is kept
+                let spawned = match self.servers.remove(&name) {
+                    Some(mut instance) => instance.respawn(config, false).map(|_| instance),
+                    None => Server::spawn(&*name, config),
+                };
+
+                match spawned {
                     Ok(instance) => {
-                        println!("\"{}\" started!", server);
-                        self.servers.insert(server, instance);
+                        try!(writeln!(w, "Server \"{}\" started!", name));
+
+                        if instance.has_ready_check() {
+                            try!(writeln!(w, "\"{}\" isn't ready yet, see `status`", name));
+                        }
+
+                        self.servers.insert(name, instance);
+                    },
+                    Err(err) => {
+                        try!(writeln!(w, "Error starting \"{}\": {}", name, err));
+                        failed.push(name);
                     },
-                    Err(err) => println!("Error auto-starting \"{}\": {}", server, err),                       
                 }
-            } else {
-                println!("No config found for \"{}\"", server);    
             }
-        }            
+        }
+
+        Ok(failed)
+    }
+
+    /// The instances of `config`'s dependencies and `after` servers that are still starting up,
+    /// and those servers that are waiting to start.
+    fn unready(&mut self, config: &ServerConfig) -> Vec<String> {
+        let mut unready = Vec::new();
+
+        for dep in config.dependencies().iter().chain(config.start_after().iter()) {
+            if self.waiting.iter().any(|start| start.server == *dep) {
+                unready.push(dep.clone());
+                continue;
+            }
+
+            for dep_instance in self.instances_of(&**dep).into_iter() {
+                let starting = self.servers.get(&dep_instance).unwrap().readiness() == Readiness::Starting;
+
+                if starting && self.is_running(&*dep_instance) {
+                    unready.push(dep_instance);
+                }
+            }
+        }
+
+        unready
+    }
+
+    /// Start what `start_all` put aside, once nothing it waits for is still starting up.
+    /// Dependencies that failed or stopped in the meantime are reported then, to the daemon's output.
+    fn start_waiting(&mut self) {
+        if self.waiting.is_empty() {
+            return;
+        }
+
+        let mut stdout = stdio::stdout();
+
+        for start in mem::replace(&mut self.waiting, Vec::new()).into_iter() {
+            let config = match self.config.servers.get(&start.server) {
+                Some(config) => config.clone(),
+                None => {
+                    println!("Not starting \"{}\": it's no longer configured", start.server);
+                    continue;
+                },
+            };
+
+            if !self.unready(&config).is_empty() {
+                self.waiting.push(start);
+                continue;
+            }
+
+            match self.start_all(&*start.instances, &mut stdout) {
+                Ok(ref failed) if failed.is_empty() => (),
+                Ok(failed) => println!("Failed to start {:?}", failed),
+                Err(err) => println!("Error starting \"{}\": {}", start.server, err),
+            }
+        }
     }
 
-    fn manage_clients(&mut self, clients: &mut Vec<ClientStream>) -> bool {
-        while let Ok(Some(client)) = ignore_timeout(self.acceptor.accept()) {
+    /// Drop `name`, a server or one instance, from the starts waiting on other servers.
+    /// Returns whether anything was dropped.
+    fn cancel_waiting(&mut self, name: &str) -> bool {
+        let mut cancelled = false;
+
+        for start in self.waiting.iter_mut() {
+            let whole = &*start.server == name;
+            let count = start.instances.len();
+
+            start.instances.retain(|instance| !whole && &**instance != name);
+            cancelled = cancelled || start.instances.len() != count;
+        }
+
+        self.waiting.retain(|start| !start.instances.is_empty());
+        cancelled
+    }
+
+    fn manage_clients(&mut self, clients: &mut Vec<Client>) -> bool {
+        while let Ok(Some(mut stream)) = ignore_timeout(self.acceptor.accept()) {
             println!("Client connected!");
-            clients.push(buffered(client));
+            stream.set_read_timeout(CLIENT_TIMEOUT);
+            clients.push(Client::new(stream));
         }
 
         let mut closed = Vec::new();
 
         for (idx, client) in clients.iter_mut().enumerate() {
-            if let Ok(Some(command)) = ignore_timeout(client.read_line()) {
-                if command.trim().is_empty() { continue; }
-
-                let args: Vec<_> = command.words().map(ToOwned::to_owned).collect();
-                println!("Received command: {:?}", args);
+            // A deadline, so it's set again each tick
+            client.stream.get_mut().set_write_timeout(CLIENT_WRITE_TIMEOUT);
+
+            let line = match ignore_timeout(client.stream.read_line()) {
+                Ok(line) => line,
+                Err(_) => {
+                    println!("Client closed connection!");
+                    closed.push(idx);
+                    continue;
+                }
+            };
 
-                match self.match_op(client, args) {
-                    Err(ClientError::Io(err)) => println!("Client IO Error: {}", err),
-                    Err(ClientError::Killed) => return false,
-                    Ok(_) => (), 
+            if let Some(line) = line {
+                if client.follow.is_some() {
+                    self.follow_input(client, &*line);
+                } else if !self.handle_line(client, &*line) {
+                    return false;
                 }
-            } else {
-                println!("Client closed connection!");
+            }
+
+            if client.follow.is_some() && !self.forward_lines(client) {
                 closed.push(idx);
-            }                 
+            }
         }
 
-        for idx in closed.into_iter() {
+        for idx in closed.into_iter().rev() {
             clients.remove(idx);    
         }
 
         true
     }
 
+    /// Parse and execute a request. Returns `false` if the daemon should exit.
+    fn handle_line(&mut self, client: &mut Client, line: &str) -> bool {
+        if line.trim().is_empty() { return true; }
+
+        let (mut resp, mut args) = match protocol::parse_line(line) {
+            Parsed::Text(args) => (Response::text(&mut client.stream), args),
+            Parsed::Json(request) => {
+                let mut resp = Response::json(&mut client.stream, request.id);
+
+                if request.version != PROTOCOL_VERSION {
+                    let msg = format!(
+                        "Unsupported protocol version {}, expected {}",
+                        request.version, PROTOCOL_VERSION
+                    );
+                    let _ = resp.fail(ErrorKind::UnsupportedVersion, msg);
+                    finish_response(resp);
+                    return true;
+                }
+
+                let mut args = request.args;
+                args.insert(0, request.op);
+                (resp, args)
+            },
+            Parsed::Invalid(id, msg) => {
+                let mut resp = Response::json(&mut client.stream, id);
+                let _ = resp.fail(ErrorKind::BadRequest, msg);
+                finish_response(resp);
+                return true;
+            },
+        };
+
+        println!("Received command: {:?}", args);
+
+        let res = match &*args[0] {
+            "follow" | "attach" => {
+                let op = args.remove(0);
+
+                match self.follow_server(&mut resp, &*op, args) {
+                    Ok(follow) => {
+                        client.follow = follow;
+                        Ok(())
+                    },
+                    Err(err) => Err(err),
+                }
+            },
+            _ => self.match_op(&mut resp, args),
+        };
+
+        // A follow stays open until the client detaches
+        if client.follow.is_some() {
+            let _ = resp.flush();
+        } else {
+            finish_response(resp);
+        }
+
+        match res {
+            Err(ClientError::Io(err)) => println!("Client IO Error: {}", err),
+            Err(ClientError::Killed) => return false,
+            Ok(_) => (), 
+        }
+
+        true
+    }
+
     fn check_instances(&mut self) {
         let mut to_remove = Vec::new();
 
         for (server, instance) in self.servers.iter_mut() {
-            if !instance.is_alive() && instance.auto_restart() {
-                println!("\"{}\" has died!\nLast five lines of log:", server);
-                for line in instance.tail(5).iter() {
-                    println!("{}", line);    
+            instance.pump_lines();
+
+            if instance.is_alive() {
+                let readiness = instance.readiness();
+                if instance.update_readiness() != readiness {
+                    println!("\"{}\": {}", server, instance.readiness());
+                }
+
+                instance.update_stats();
+
+                if !instance.update_health() { continue; }
+
+                println!("\"{}\" is not responding to health checks! Stopping...", server);
+
+                match instance.stop() {
+                    Ok(exit_status) => println!(
+                        "\"{}\" stopped. Status: {}{}", server, exit_status, describe_exit(instance)
+                    ),
+                    Err(err) => {
+                        println!("Failed to stop \"{}\"! Message: {}", server, err);
+                        continue;
+                    },
                 }
 
-                if let Some(config) = self.config.servers.get(server).cloned() {
-                    println!("Restarting \"{}\"...", server);
-                    *instance = match Server::spawn(config) {
-                        Ok(new_instance) => new_instance,
-                        Err(err) => {
+                report_restart(&**server, instance.schedule_restart(true));
+                continue;
+            }
+
+            match instance.restart_state() {
+                RestartState::Idle => {
+                    println!("\"{}\" has died{}!\nLast five lines of log:", server, describe_exit(instance));
+                    for line in instance.tail(5, None).iter() {
+                        println!("{}", line);    
+                    }
+
+                    // Orphaned children could hold on to ports the new process needs
+                    instance.kill_leftovers();
+
+                    instance.crashed();
+
+                    report_restart(&**server, instance.schedule_restart(false));
+                },
+                RestartState::Pending(at) if at <= precise_time_ms() => {
+                    if let Some(config) = self.config.instance_config(&**server) {
+                        println!("Restarting \"{}\"...", server);
+
+                        // The failed attempt counted towards the limits, so this gives up eventually
+                        if let Err(err) = instance.respawn(config, true) {
                             println!("Error restarting \"{}\": {}", server, err);
-                            continue;
+                            report_restart(&**server, instance.schedule_restart(true));
                         }
-                    }
-                } else {
-                    println!("Lost config for \"{}\"!", server);
-                    to_remove.push(server.clone());    
-                }                 
+                    } else {
+                        println!("Lost config for \"{}\"!", server);
+                        to_remove.push(server.clone());    
+                    }                 
+                },
+                _ => (),
             }
         }
 
@@ -154,224 +577,520 @@ impl Daemon {
         }
     }
 
-    fn match_op(&mut self, client: &mut ClientStream, mut args: Vec<String>) -> ClientResult<()> {
+    /// Whether `server` has a live process. Instances that have exited may linger
+    /// while they wait to be restarted.
+    fn is_running(&mut self, server: &str) -> bool {
+        self.servers.get_mut(server).map_or(false, |instance| instance.is_alive())
+    }
+
+    fn match_op(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
         if !args.is_empty() {
             let op = args.remove(0);
 
             match &*op {
-                "start" => self.start_server(client, args),
-                "stop" => self.stop_server(client, args),
-                "restart" => self.restart_server(client, args),
-                "status" => self.server_status(client, args),
-                "tail" => self.server_tail(client, args),
-                "send" => self.server_send(client, args),
-                "servers" => self.list_servers(client),
-                "instances"=> self.list_instances(client),
-                "reload-config" => self.reload_config(client),
-                "kill-daemon" => self.kill_daemon(client),
-                "ops" => list_ops(client),
+                "start" => self.start_server(resp, args),
+                "stop" => self.stop_server(resp, args),
+                "restart" => self.restart_server(resp, args),
+                "status" => self.server_status(resp, args),
+                "tail" => self.server_tail(resp, args),
+                "stats" => self.server_stats(resp, args),
+                "ps" => self.server_ps(resp, args),
+                "schedule" => self.schedule_op(resp, args),
+                "send" => self.server_send(resp, args),
+                "scale" => self.scale_server(resp, args),
+                "servers" => self.list_servers(resp),
+                "instances"=> self.list_instances(resp),
+                "reload-config" => self.reload_config(resp, args),
+                "config-path" => self.config_path(resp),
+                "check-config" => self.check_config(resp),
+                "kill-daemon" => self.kill_daemon(resp),
+                "ops" => list_ops(resp),
                 _ => {
-                    ce(writeln!(client, "Unrecognized command: {}", op))
-                        .and_then(|_| list_ops(client))
+                    try!(fail(resp, ErrorKind::UnknownOp, format!("Unrecognized command: {}", op)));
+                    list_ops(resp)
                 },
             }
         } else {
-            list_ops(client)    
-        }.and_then(|&mut: _| ce(client.flush()))
+            list_ops(resp)    
+        }.and_then(|_| ce(resp.flush()))
     }
 
-    fn start_server(&mut self, client: &mut ClientStream, mut args: Vec<String>) -> ClientResult<()> {
+    fn start_server(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
         if args.is_empty() {
-            return ce(client.write_line("Usage: start <server>"));        }
+            return usage(resp, "start <server>");
+        }
 
         let server = args.remove(0);
-                
-        ce(match self.servers.entry(server.clone()) {
-            Entry::Occupied(_) => writeln!(client, "Server \"{}\" already running!", server),
-            Entry::Vacant(vacant) => {
-                if let Some(config) = self.config.servers.get(&*server) {
-                    try!(writeln!(client, "Starting \"{}\"...", server).and_then(|_| client.flush()));
-                    match Server::spawn(config.clone()) {
-                        Ok(instance) => {
-                            vacant.insert(instance);
-                            writeln!(client, "Server \"{}\" started!", server)
-                        },
-                        Err(err) => writeln!(client, "Error starting \"{}\": {:?}", server, err),
-                    }
-                } else {
-                    writeln!(client, "No configuration for \"{}\"", server)
-                }
-            }
-        })
+
+        if self.config.instance_config(&*server).is_none() {
+            return no_config(resp, &*server);
+        }
+
+        if !self.in_scale(&*server) {
+            let base = split_instance(&*server).0;
+            return fail(resp, ErrorKind::Failed, format!(
+                "\"{}\" is beyond the {} instances of \"{}\", use `scale` to run more",
+                server, self.instance_names(base).len(), base
+            ));
+        }
+
+        let instances = match split_instance(&*server) {
+            (_, Some(_)) => vec![server.clone()],
+            (base, None) => self.instance_names(base),
+        };
+
+        if instances.is_empty() {
+            return ce(writeln!(resp, "\"{}\" is scaled to 0 instances, nothing to start.", server));
+        }
+
+        if instances.iter().all(|instance| self.is_running(&**instance)) {
+            return fail(resp, ErrorKind::AlreadyRunning, format!("Server \"{}\" already running!", server));
+        }
+
+        self.start_and_report(resp, server)
+    }
+
+    fn start_and_report(&mut self, resp: &mut Response, server: String) -> ClientResult<()> {
+        let failed = try!(self.start_all(&[server], resp));
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            fail(resp, ErrorKind::Failed, format!("Failed to start {:?}", failed))
+        }
     }
 
-    fn stop_server(&mut self, client: &mut ClientStream, mut args: Vec<String>) -> ClientResult<()> {
+    fn stop_server(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
+        const USAGE: &'static str = "stop <server> [--with-dependents]";
+
         if args.is_empty() {
-            return ce(client.write_line("Usage: stop <server>"));
+            return usage(resp, USAGE);
         }
 
         let server = args.remove(0);
 
-        if let Some(mut instance) = self.servers.remove(&*server) {
-            stop_server(&*server, &mut instance, client).map(|_| ())            
-        } else {
-            ce(writeln!(client, "No running instance of \"{}\"", server))
+        let with_dependents = match args.get(0).map(|s| &**s) {
+            Some("--with-dependents") => true,
+            Some(_) => return usage(resp, USAGE),
+            None => false,
+        };
+
+        let cancelled = self.cancel_waiting(&*server);
+        if cancelled {
+            try!(writeln!(resp, "\"{}\" is no longer waiting to start.", server));
         }
+
+        let instances: Vec<String> = self.instances_of(&*server).into_iter()
+            .filter(|name| self.is_active(&**name))
+            .collect();
+
+        if instances.is_empty() {
+            return if cancelled { Ok(()) } else { not_running(resp, &*server) };
+        }
+
+        // Dependents only lose their dependency once no instance of it is left running
+        let base = split_instance(&*server).0;
+        let stopping_all = self.instances_of(base).into_iter()
+            .all(|name| instances.contains(&name) || !self.is_running(&*name));
+
+        let dependents: Vec<_> = if stopping_all { self.config.dependents(base) } else { Vec::new() }
+            .into_iter()
+            .filter(|dependent| self.is_up(&**dependent))
+            .collect();
+
+        if with_dependents {
+            for dependent in dependents.iter() {
+                for name in self.instances_of(&**dependent).into_iter() {
+                    if !self.is_active(&*name) { continue; }
+
+                    // Stopped instances stay, keeping their exit history for `status` and the next start
+                    if !try!(stop_server(&*name, self.servers.get_mut(&name).unwrap(), resp)) {
+                        return Ok(());
+                    }
+                }
+            }
+        } else if !dependents.is_empty() {
+            try!(writeln!(
+                resp, "Warning: {:?} depend on \"{}\" and are still running. Use `--with-dependents` to stop them too.",
+                dependents, server
+            ));
+        }
+
+        // Stopping a lingering instance cancels its pending restart, see `Server::stop`
+        for name in instances.into_iter() {
+            if !try!(stop_server(&*name, self.servers.get_mut(&name).unwrap(), resp)) {
+                return Ok(());
+            }
+        }
+
+        Ok(())
     }
 
-    fn restart_server(&mut self, client: &mut ClientStream, mut args: Vec<String>) -> ClientResult<()> {
+    fn restart_server(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
         if args.is_empty() {
-            return ce(client.write_line("Usage: restart <server>"));
+            return usage(resp, "restart <server>");
         }
 
         let server = args.remove(0);
+        let instances: Vec<String> = self.instances_of(&*server).into_iter()
+            .filter(|name| self.is_active(&**name))
+            .collect();
 
-        if let Some(mut instance) = self.servers.remove(&*server) {
-            if !try!(stop_server(&*server, &mut instance, client)) {
+        // `start_all` respawns the stopped instances, carrying their history over
+        for name in instances.iter() {
+            if !try!(stop_server(&**name, self.servers.get_mut(name).unwrap(), resp)) {
                 return Ok(());  
             }
-        } else {
-            try!(writeln!(client, "\"{}\" was not running! Starting anyways...", server));
+        }
+
+        if instances.is_empty() {
+            try!(writeln!(resp, "\"{}\" was not running! Starting anyways...", server));
         }
          
         // Start server
-        ce(if let Some(config) = self.config.servers.get(&*server) {
-            let instance = try!(Server::spawn(config.clone()));
-            let res = writeln!(client, "Server \"{}\" started!", server);
-            self.servers.insert(server, instance);
-            res
+        if self.config.instance_config(&*server).is_some() {
+            self.start_and_report(resp, server)
         } else {
-            writeln!(client, "No configuration for \"{}\"! Did the configuration change?", server)
-        })
+            fail(resp, 
+                ErrorKind::NoConfig,
+                format!("No configuration for \"{}\"! Did the configuration change?", server)
+            )
+        }
     }
 
-    fn server_status(&mut self, client: &mut ClientStream, mut args: Vec<String>) -> ClientResult<()> {
+    fn server_status(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
         if args.is_empty() {
-            return ce(client.write_line("Usage: status <server>")); 
+            return usage(resp, "status <server>");
         }
 
         let server = args.remove(0);
+        let instances = self.instances_of(&*server);
 
-        ce(if let Some(instance) = self.servers.get_mut(&*server) {
-            try!(writeln!(client, "Server instance \"{}\" ", server));
-            instance.write_status(client)
-        } else {
-            writeln!(client, "No running instance of \"{}\"", server)
-        })
+        if instances.is_empty() {
+            return not_running(resp, &*server);
+        }
+
+        let processes = try!(self.process_table());
+
+        for name in instances.iter() {
+            let instance = self.servers.get_mut(name).unwrap();
+            try!(writeln!(resp, "Server instance \"{}\" ", name));
+            try!(instance.write_status(resp, &*processes));
+            try!(instance.write_exit_history(resp));
+        }
+
+        Ok(())
     }
 
-    fn server_tail(&mut self, client: &mut ClientStream, mut args: Vec<String>) -> ClientResult<()> {
+    fn server_tail(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
         const DEFAULT_LINE_COUNT: usize = 20;
         
         if args.is_empty() {
-            return ce(client.write_line("Usage: tail <server> [lines]"));
+            return usage(resp, "tail <server> [lines] [--stdout|--stderr]");
         }
 
         let server = args.remove(0);
-        let lines = args.get(0).and_then(|s| s.parse()).unwrap_or(DEFAULT_LINE_COUNT);
+
+        let mut lines = DEFAULT_LINE_COUNT;
+        let mut stream = None;
+
+        for arg in args.iter() {
+            match &**arg {
+                "--stdout" => stream = Some(Stream::Stdout),
+                "--stderr" => stream = Some(Stream::Stderr),
+                _ => match arg.parse() {
+                    Some(count) => lines = count,
+                    None => return usage(resp, "tail <server> [lines] [--stdout|--stderr]"),
+                },
+            }
+        }
+
+        let server = match try!(self.resolve_one(resp, &*server)) {
+            Some(server) => server,
+            None => return Ok(()),
+        };
 
         if let Some(instance) = self.servers.get_mut(&*server) {
-            try!(writeln!(client, "Last {} lines from \"{}\":", lines, server).and_then(|_| client.flush()));
-            for line in instance.tail(lines).iter() {
-                try!(client.write_str(&**line));    
+            let from = stream.map_or(String::new(), |stream| format!(" of {}", stream));
+            try!(writeln!(resp, "Last {} lines{} from \"{}\":", lines, from, server).and_then(|_| resp.flush()));
+            for line in instance.tail(lines, stream).iter() {
+                try!(writeln!(resp, "{}", line));    
             }
 
             Ok(())
         } else {
-            ce(writeln!(client, "No running instance of \"{}\"", server))
+            not_running(resp, &*server)
+        }
+    }
+
+    fn server_stats(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
+        const DEFAULT_SAMPLE_COUNT: usize = 12;
+
+        if args.is_empty() {
+            return usage(resp, "stats <server> [samples]");
+        }
+
+        let server = args.remove(0);
+
+        let count = match args.get(0).map(|arg| arg.parse()) {
+            Some(Some(count)) => count,
+            Some(None) => return usage(resp, "stats <server> [samples]"),
+            None => DEFAULT_SAMPLE_COUNT,
+        };
+
+        let server = match try!(self.resolve_one(resp, &*server)) {
+            Some(server) => server,
+            None => return Ok(()),
+        };
+
+        if let Some(instance) = self.servers.get(&*server) {
+            let samples = instance.stats(count);
+
+            if samples.is_empty() {
+                return ce(writeln!(resp, "No samples taken for \"{}\" yet.", server));
+            }
+
+            try!(writeln!(resp, "Last {} samples from \"{}\":", samples.len(), server));
+            try!(resp.write_line(Sample::header()));
+
+            for sample in samples.iter() {
+                try!(writeln!(resp, "{}", sample));
+            }
+
+            Ok(())
+        } else {
+            not_running(resp, &*server)
+        }
+    }
+
+    fn server_ps(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
+        if args.is_empty() {
+            return usage(resp, "ps <server>");
+        }
+
+        let server = match try!(self.resolve_one(resp, &*args.remove(0))) {
+            Some(server) => server,
+            None => return Ok(()),
+        };
+
+        let processes = try!(self.process_table());
+
+        // Not a match guard, `is_alive` needs a mutable borrow
+        let instance = match self.servers.get_mut(&*server) {
+            Some(instance) => instance,
+            None => return not_running(resp, &*server),
+        };
+
+        if !instance.is_alive() {
+            return not_running(resp, &*server);
         }
+
+        try!(writeln!(resp, "Processes of \"{}\":", server));
+        try!(resp.write_line(ProcessEntry::header()));
+
+        for process in instance.process_tree(&*processes).iter() {
+            try!(writeln!(resp, "{}", process));
+        }
+
+        Ok(())
     }
 
-    fn server_send(&mut self, client: &mut ClientStream, mut args: Vec<String>) -> ClientResult<()> {
+    fn server_send(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
         if args.is_empty() {
-            return ce(client.write_line("Usage: send <server> <command>"));
+            return usage(resp, "send <server> <command>");
         }
 
         let server = args.remove(0);
         let command = args.connect(" ");
+        let instances = self.instances_of(&*server);
+
+        if instances.is_empty() {
+            return not_running(resp, &*server);
+        }
 
-        ce(if let Some(instance) = self.servers.get_mut(&*server) {
-            try!(writeln!(client, "Sending command to \"{}\": {}", server, command).and_then(|_| client.flush()));
+        for name in instances.iter() {
+            let instance = self.servers.get_mut(name).unwrap();
+            try!(writeln!(resp, "Sending command to \"{}\": {}", name, command).and_then(|_| resp.flush()));
             try!(instance.send_command(&*command));
-            if let Some(line) = instance.tail(1).get(0) {
-                writeln!(client, "\"{}\": {}", server, line)
-            } else {
-                Ok(())
+            if let Some(line) = instance.tail(1, None).get(0) {
+                try!(writeln!(resp, "\"{}\": {}", name, line));
             }
-        } else {
-            writeln!(client, "No running instance of \"{}\"", server)
-        })
+        }
+
+        Ok(())
     }
 
-    fn list_servers(&mut self, client: &mut ClientStream) -> ClientResult<()> {
-        try!(client.write_line("Servers:"));
+    fn list_servers(&mut self, resp: &mut Response) -> ClientResult<()> {
+        try!(resp.write_line("Servers:"));
         for (server, config) in self.config.servers.iter() {
-            try!(writeln!(client, "\"{}\":\n{:?}", server, config));    
+            try!(writeln!(resp, "\"{}\":\n{:?}", server, config));    
         }
         Ok(())
     }
 
-    fn list_instances(&mut self, client: &mut ClientStream) -> ClientResult<()> {
-        try!(client.write_line("Running instances:"));
+    fn list_instances(&mut self, resp: &mut Response) -> ClientResult<()> {
+        try!(resp.write_line("Instances:"));
+        let processes = try!(self.process_table());
+
         for (server, instance) in self.servers.iter_mut() {
-            try!(write!(client, "\"{}\" ", server));
-            try!(instance.write_status(client));    
+            try!(write!(resp, "\"{}\" ", server));
+            try!(instance.write_status(resp, &*processes));    
         }
         Ok(())        
     }
 
-    fn reload_config(&mut self, client: &mut ClientStream) -> ClientResult<()> {
-        try!(
-            client.write_line("Reloading config. Will not affect existing server instances.")
-                .and_then(|_| client.flush())
-        );
-
-        self.config = match Config::load() {
-            Ok(config) => config,
-            Err(err) => return ce(writeln!(client, "Failed to load config: {:?}", err)),
-        };
+    fn config_path(&mut self, resp: &mut Response) -> ClientResult<()> {
+        ce(writeln!(resp, "{}", self.config.path.display()))
+    }
 
-        ce(client.write_line("Config reloaded."))
+    /// Check the config file as a reload would load it
+    fn check_config(&mut self, resp: &mut Response) -> ClientResult<()> {
+        if try!(config::write_check(&self.config.path, resp)) {
+            Ok(())
+        } else {
+            fail(resp, ErrorKind::Failed, "The config has errors or problems".to_string())
+        }
     }
 
-    fn kill_daemon(&mut self, client: &mut ClientStream) -> ClientResult<()> { 
-        try!(client.write_line("Killing servers..."));
-        for (server, mut instance) in self.servers.drain() {
-            let _  = stop_server(&*server, &mut instance, client);
-            try!(client.flush())
+    fn kill_daemon(&mut self, resp: &mut Response) -> ClientResult<()> { 
+        try!(resp.write_line("Killing servers..."));
+
+        let failed = try!(self.stop_everything(resp));
+
+        if !failed.is_empty() {
+            try!(fail(resp, ErrorKind::Failed, format!("Failed to stop {:?}", failed)));
         }
 
         try!(
-            client.write_line("Daemon exiting. Any servers that failed to stop will die now.")
-                .and_then(|_| client.flush())
-                .and_then(|_| client.write(&[0; 64]))
+            resp.write_line("Daemon exiting. Any servers that failed to stop will die now.")
+                .and_then(|_| resp.flush())
         );
 
         Err(ClientError::Killed)
     }
+
+    /// Stop every instance, dependents before the servers they depend on, reporting to `w`.
+    /// Returns the instances that failed to stop.
+    fn stop_everything(&mut self, w: &mut Writer) -> IoResult<Vec<String>> {
+        self.notify("STOPPING=1");
+
+        let mut running: Vec<_> = self.servers.keys().map(|name| split_instance(&**name).0.to_string()).collect();
+        running.sort();
+        running.dedup();
+
+        let mut stop_order = self.config.start_order(&*running);
+        stop_order.reverse();
+
+        let mut names = Vec::new();
+        for server in stop_order.iter() {
+            names.extend(self.instances_of(&**server).into_iter());
+        }
+
+        // Anything whose config was removed by a reload
+        let mut rest: Vec<String> = self.servers.keys().filter(|name| !names.contains(*name)).cloned().collect();
+        rest.sort();
+        names.extend(rest.into_iter());
+
+        let mut failed = Vec::new();
+
+        for name in names.into_iter() {
+            if !self.is_running(&*name) { continue; }
+
+            let mut instance = self.servers.remove(&name).unwrap();
+
+            try!(writeln!(w, "Sending stop command to \"{}\"...", name).and_then(|_| w.flush()));
+
+            match instance.stop() {
+                Ok(exit_status) => try!(writeln!(
+                    w, "\"{}\" stopped. Status: {}{}", name, exit_status, describe_exit(&instance)
+                )),
+                Err(err) => {
+                    try!(writeln!(w, "Failed to stop \"{}\"! Message: {}", name, err));
+                    failed.push(name);
+                },
+            }
+        }
+
+        Ok(failed)
+    }
 }
 
-fn stop_server(server: &str, instance: &mut Server, client: &mut ClientStream) -> ClientResult<bool> {
-    try!(writeln!(client, "Sending stop command to \"{}\"...", server).and_then(|_| client.flush()));
-    ce(match instance.stop() {
-        Ok(exit_status) => writeln!(client, "\"{}\" stopped. Status: {}", server, exit_status)
+/// Describe the instance's last exit, e.g. " (killed by signal 9 (SIGKILL); memory.max reached, 1 OOM kill(s))"
+fn describe_exit(instance: &Server) -> String {
+    instance.last_exit().map_or(String::new(), |record| match record.limit_hits {
+        Some(ref hits) => format!(" ({}; {})", FormatExit(&record.exit), hits),
+        None => format!(" ({})", FormatExit(&record.exit)),
+    })
+}
+
+fn report_restart(server: &str, state: RestartState) {
+    match state {
+        RestartState::Pending(at) => {
+            let now = precise_time_ms();
+            let delay = if at > now { at - now } else { 0 };
+            println!("Restarting \"{}\" in {}ms.", server, delay);
+        },
+        RestartState::GaveUp => println!("\"{}\" is crash-looping! Giving up on restarting it.", server),
+        RestartState::NotRestarting => println!("Not restarting \"{}\" per its restart policy.", server),
+        RestartState::Idle | RestartState::Stopped => (),
+    }
+}
+
+fn stop_server(server: &str, instance: &mut Server, resp: &mut Response) -> ClientResult<bool> {
+    try!(writeln!(resp, "Sending stop command to \"{}\"...", server).and_then(|_| resp.flush()));
+    match instance.stop() {
+        Ok(exit_status) => ce(writeln!(
+            resp, "\"{}\" stopped. Status: {}{}", server, exit_status, describe_exit(instance)
+        ))
             .map(|_| true),
-        Err(err) => writeln!(client, "Failed to stop \"{}\"! Message: {}", server, err)
+        Err(err) => fail(resp, ErrorKind::Failed, format!("Failed to stop \"{}\"! Message: {}", server, err))
             .map(|_| false),
-    })
+    }
+}
+
+fn fail(resp: &mut Response, kind: ErrorKind, message: String) -> ClientResult<()> {
+    ce(resp.fail(kind, message))
+}
+
+fn finish_response(resp: Response) {
+    if let Err(err) = resp.finish() {
+        println!("Client IO Error: {}", err);
+    }
+}
+
+fn usage(resp: &mut Response, usage: &str) -> ClientResult<()> {
+    fail(resp, ErrorKind::Usage, format!("Usage: {}", usage))
+}
+
+fn not_running(resp: &mut Response, server: &str) -> ClientResult<()> {
+    fail(resp, ErrorKind::NotRunning, format!("No running instance of \"{}\"", server))
+}
+
+fn no_config(resp: &mut Response, server: &str) -> ClientResult<()> {
+    fail(resp, ErrorKind::NoConfig, format!("No configuration for \"{}\"", server))
 }
 
 fn list_ops(writer: &mut Writer) -> ClientResult<()> {
     ce(writer.write_line(r#"
 shepherd ops:
     start <server>
-    stop <server> 
+    stop <server> [--with-dependents]
     restart <server>
-    tail <server> [lines]
-    send <server> <commandt
+    tail <server> [lines] [--stdout|--stderr]
+    send <server> <command>
+    scale <server> <count>
     status <server>
+    stats <server> [samples]
+    ps <server>
+    schedule list [server]
+    schedule run-now <server> <number>
     servers
     instances
+    follow <server> [lines]
+    attach <server> [lines]
+    reload-config [--dry-run|--apply]
+    config-path
+    check-config
     ops
     kill-daemon
 "#))  
@@ -395,5 +1114,3 @@ impl FromError<IoError> for ClientError {
 fn ce<T>(res: Result<T, IoError>) -> ClientResult<T> {
     res.map_err(FromError::from_error)    
 }
-
-
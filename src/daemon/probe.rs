@@ -0,0 +1,350 @@
+//! Probes used to decide whether a server is ready, and whether it's still healthy afterwards.
+
+use config::ProbeConfig;
+use super::supervise::track_child;
+use util::precise_time_ms;
+
+use regex::Regex;
+
+use std::fmt;
+use std::io::net::pipe::UnixStream;
+use std::io::net::tcp::TcpStream;
+use std::io::process::{Command, StdioContainer};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread::Thread;
+use std::time::Duration;
+
+/// Default time a server gets to pass its readiness probe, in seconds
+pub const DEFAULT_TIMEOUT: u64 = 60;
+/// Default time between readiness attempts for probes that aren't log patterns, in seconds
+pub const DEFAULT_INTERVAL: u64 = 1;
+
+/// Default time between health checks, in seconds
+pub const DEFAULT_HEALTH_INTERVAL: u64 = 10;
+/// Default time each health check may take, in seconds
+pub const DEFAULT_HEALTH_TIMEOUT: u64 = 5;
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+/// Readiness connection and exec attempts get at most this long, in ms
+const ATTEMPT_TIMEOUT: u64 = 1000;
+
+#[derive(Clone)]
+pub enum Probe {
+    /// Passes once an output line matches
+    LogPattern(Regex),
+    /// Passes once a TCP connection to the address is accepted
+    Tcp(String),
+    /// Passes once a connection to the socket is accepted
+    Unix(String),
+    /// Passes once the command exits successfully; run in the server's directory
+    Exec(Vec<String>),
+}
+
+impl Probe {
+    /// Build a probe from its config, checking it with `ProbeConfig::validate()` first.
+    pub fn from_config(config: &ProbeConfig) -> Result<Probe, String> {
+        try!(config.validate());
+
+        if let Some(ref pattern) = config.log_pattern {
+            return Ok(Probe::LogPattern(Regex::new(&**pattern).unwrap()));
+        }
+
+        Ok(match (&config.tcp, &config.unix_socket, &config.exec) {
+            (&Some(ref addr), _, _) => Probe::Tcp(addr.clone()),
+            (_, &Some(ref path), _) => Probe::Unix(path.clone()),
+            (_, _, &Some(ref command)) => Probe::Exec(command.clone()),
+            _ => unreachable!(),
+        })
+    }
+
+    /// Something for the server's output readers to match lines against, for log patterns.
+    pub fn watch(&self) -> Option<LogWatch> {
+        match *self {
+            Probe::LogPattern(ref regex) => Some(LogWatch {
+                regex: regex.clone(),
+                matched: Arc::new(AtomicBool::new(false)),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Make one attempt at a connection or exec probe, taking at most `timeout_ms`.
+    /// Always fails for log patterns.
+    pub fn attempt(&self, dir: &str, timeout_ms: u64) -> bool {
+        let timeout = Duration::milliseconds(timeout_ms as i64);
+
+        match *self {
+            Probe::LogPattern(_) => false,
+            Probe::Tcp(ref addr) => TcpStream::connect_timeout(&**addr, timeout).is_ok(),
+            Probe::Unix(ref path) => UnixStream::connect_timeout(&**path, timeout).is_ok(),
+            Probe::Exec(ref command) => {
+                let mut process = match Command::new(&*command[0]).args(&command[1..])
+                    .cwd(&Path::new(dir))
+                    .stdin(StdioContainer::Ignored)
+                    .stdout(StdioContainer::Ignored)
+                    .stderr(StdioContainer::Ignored)
+                    .spawn() {
+                    Ok(process) => process,
+                    Err(_) => return false,
+                };
+                let _tracked = track_child(process.id());
+
+                process.set_timeout(Some(timeout_ms));
+
+                match process.wait() {
+                    Ok(status) => status.success(),
+                    Err(_) => {
+                        let _ = process.signal_kill();
+                        let _ = process.wait();
+                        false
+                    }
+                }
+            },
+        }
+    }
+
+    /// Make an attempt on another thread, so it doesn't hold up the daemon. The result is sent to the receiver;
+    /// if nobody's listening by then, the send fails harmlessly.
+    pub fn attempt_threaded(&self, dir: &str, timeout_ms: u64) -> Receiver<bool> {
+        let (tx, rx) = channel();
+        let probe = self.clone();
+        let dir = dir.to_string();
+
+        Thread::spawn(move || { let _ = tx.send(probe.attempt(&*dir, timeout_ms)); });
+
+        rx
+    }
+}
+
+/// A log pattern shared with the threads reading a server's output. Lines are matched as they're read,
+/// so none are missed however many arrive between checks.
+#[derive(Clone)]
+pub struct LogWatch {
+    regex: Regex,
+    matched: Arc<AtomicBool>,
+}
+
+impl LogWatch {
+    /// Called by the output readers with each line.
+    pub fn check(&self, line: &str) {
+        if !self.matched.load(SeqCst) && self.regex.is_match(line) {
+            self.matched.store(true, SeqCst);
+        }
+    }
+
+    /// Whether a line has matched since the last call.
+    fn take(&self) -> bool {
+        self.matched.swap(false, SeqCst)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum Readiness {
+    /// The probe hasn't passed yet, but there's still time
+    Starting,
+    Ready,
+    /// The probe didn't pass before the timeout
+    NotReady,
+}
+
+impl fmt::String for Readiness {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.pad(match *self {
+            Readiness::Starting => "Starting",
+            Readiness::Ready => "Ready",
+            Readiness::NotReady => "Failed to become ready",
+        })
+    }
+}
+
+/// Tracks a server's progress towards passing its readiness probe.
+pub struct ReadyCheck {
+    probe: Probe,
+    started: u64,
+    timeout: u64,
+    interval: u64,
+    last_attempt: Option<u64>,
+    /// The result of a connection or exec attempt still running on its own thread
+    attempt: Option<Receiver<bool>>,
+    /// Set for log patterns
+    watch: Option<LogWatch>,
+    state: Readiness,
+}
+
+impl ReadyCheck {
+    /// The config must already have been validated by `ProbeConfig::validate()`, which happens at load.
+    pub fn new(config: &ProbeConfig) -> ReadyCheck {
+        let probe = Probe::from_config(config).ok().expect("Probe config was not validated");
+
+        ReadyCheck {
+            watch: probe.watch(),
+            probe: probe,
+            started: precise_time_ms(),
+            timeout: config.timeout.unwrap_or(DEFAULT_TIMEOUT) * 1000,
+            interval: config.interval.unwrap_or(DEFAULT_INTERVAL) * 1000,
+            last_attempt: None,
+            attempt: None,
+            state: Readiness::Starting,
+        }
+    }
+
+    pub fn state(&self) -> Readiness {
+        self.state
+    }
+
+    /// Run the probe if it's due. A log pattern has passed if the output readers have seen a matching line.
+    /// Connection and exec attempts run on their own thread, their result is picked up by a later call.
+    pub fn update(&mut self, dir: &str) -> Readiness {
+        if self.state != Readiness::Starting {
+            return self.state;
+        }
+
+        let now = precise_time_ms();
+
+        let passed = if let Some(ref watch) = self.watch {
+            watch.take()
+        } else {
+            let finished = self.attempt.as_ref().map(|attempt| attempt.try_recv());
+
+            match finished {
+                Some(Ok(passed)) => {
+                    self.attempt = None;
+                    passed
+                },
+                Some(Err(TryRecvError::Empty)) => false,
+                // The attempt's thread panicked
+                Some(Err(TryRecvError::Disconnected)) => {
+                    self.attempt = None;
+                    false
+                },
+                None => {
+                    if self.last_attempt.map_or(true, |last| now - last >= self.interval) {
+                        self.last_attempt = Some(now);
+                        self.attempt = Some(self.probe.attempt_threaded(dir, ATTEMPT_TIMEOUT));
+                    }
+                    false
+                },
+            }
+        };
+
+        if passed {
+            self.state = Readiness::Ready;
+        } else if now - self.started > self.timeout {
+            self.state = Readiness::NotReady;
+        }
+
+        self.state
+    }
+
+    /// To be given to the server's output readers.
+    pub fn watch(&self) -> Option<LogWatch> {
+        self.watch.clone()
+    }
+}
+
+/// Periodically checks that a ready server is still working.
+pub struct HealthCheck {
+    probe: Probe,
+    interval: u64,
+    timeout: u64,
+    threshold: u32,
+    restart: bool,
+    /// `None` until the server is ready
+    last_check: Option<u64>,
+    /// The result of a connection or exec attempt still running on its own thread
+    attempt: Option<Receiver<bool>>,
+    /// Set for log patterns
+    watch: Option<LogWatch>,
+    failures: u32,
+}
+
+impl HealthCheck {
+    /// The config must already have been validated by `ProbeConfig::validate()`, which happens at load.
+    pub fn new(config: &ProbeConfig) -> HealthCheck {
+        let probe = Probe::from_config(config).ok().expect("Probe config was not validated");
+
+        HealthCheck {
+            watch: probe.watch(),
+            probe: probe,
+            interval: config.interval.unwrap_or(DEFAULT_HEALTH_INTERVAL) * 1000,
+            timeout: config.timeout.unwrap_or(DEFAULT_HEALTH_TIMEOUT) * 1000,
+            threshold: config.failure_threshold.unwrap_or(DEFAULT_FAILURE_THRESHOLD),
+            restart: config.restart.unwrap_or(true),
+            last_check: None,
+            attempt: None,
+            failures: 0,
+        }
+    }
+
+    /// Run the check if it's due, only calling this once the server is ready. The first check happens
+    /// one interval after the first call.
+    ///
+    /// Returns whether a check finished and passed, or `None` if none did. Connection and exec attempts
+    /// run on their own thread so they don't hold up the daemon; their result is picked up by a later call.
+    /// A log pattern passes if the output readers have seen a matching line since the last check.
+    pub fn update(&mut self, dir: &str) -> Option<bool> {
+        let now = precise_time_ms();
+
+        let finished = self.attempt.as_ref().map(|attempt| attempt.try_recv());
+
+        let passed = match finished {
+            Some(Ok(passed)) => passed,
+            Some(Err(TryRecvError::Empty)) => return None,
+            // The attempt's thread panicked
+            Some(Err(TryRecvError::Disconnected)) => false,
+            None => {
+                match self.last_check {
+                    Some(last_check) if now - last_check < self.interval => return None,
+                    Some(_) => (),
+                    None => {
+                        self.last_check = Some(now);
+                        return None;
+                    },
+                }
+
+                self.last_check = Some(now);
+
+                let matched = self.watch.as_ref().map(|watch| watch.take());
+
+                match matched {
+                    Some(matched) => matched,
+                    None => {
+                        self.attempt = Some(self.probe.attempt_threaded(dir, self.timeout));
+                        return None;
+                    },
+                }
+            },
+        };
+
+        self.attempt = None;
+
+        if passed {
+            self.failures = 0;
+        } else {
+            self.failures += 1;
+        }
+
+        Some(passed)
+    }
+
+    /// To be given to the server's output readers.
+    pub fn watch(&self) -> Option<LogWatch> {
+        self.watch.clone()
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether enough checks in a row have failed to consider the server hung.
+    pub fn is_hung(&self) -> bool {
+        self.failures >= self.threshold
+    }
+
+    pub fn restart_when_hung(&self) -> bool {
+        self.restart
+    }
+}
@@ -0,0 +1,193 @@
+//! The protocol spoken between `RemoteDaemon` and the daemon.
+//!
+//! Each request is one line holding a JSON-encoded `Request`. The daemon answers with
+//! any number of `Reply` lines carrying output, followed by exactly one `Reply` with `end` set,
+//! which carries the typed result of the op. Replies always echo the request's `id`.
+//!
+//! Lines that don't start with `{` are treated as plain-text commands and answered
+//! with free-form text, for interactive use with tools like `socat`.
+
+use super::ClientStream;
+
+use rustc_serialize::json;
+
+use std::io::IoResult;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Typed by the user on its own line to detach from `follow` and `attach` in text mode.
+pub const DETACH_ESCAPE: &'static str = "~.";
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct Request {
+    pub version: u32,
+    pub id: u64,
+    pub op: String,
+    pub args: Vec<String>,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct Reply {
+    pub id: u64,
+    pub line: Option<String>,
+    pub end: Option<Outcome>,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct Outcome {
+    pub ok: bool,
+    pub error: Option<OpError>,
+}
+
+#[derive(Clone, Show, RustcEncodable, RustcDecodable)]
+pub struct OpError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+#[derive(Copy, Clone, PartialEq, Show, RustcEncodable, RustcDecodable)]
+pub enum ErrorKind {
+    BadRequest,
+    UnsupportedVersion,
+    UnknownOp,
+    Usage,
+    NoConfig,
+    NotRunning,
+    AlreadyRunning,
+    Failed,
+}
+
+/// A parsed request line, in whichever mode the client used.
+pub enum Parsed {
+    Json(Request),
+    Text(Vec<String>),
+    /// The line looked like JSON but couldn't be decoded. Carries the best-guess ID.
+    Invalid(u64, String),
+}
+
+pub fn parse_line(line: &str) -> Parsed {
+    use std::borrow::ToOwned;
+
+    let line = line.trim();
+
+    if !line.starts_with("{") {
+        return Parsed::Text(line.words().map(ToOwned::to_owned).collect());
+    }
+
+    match json::decode::<Request>(line) {
+        Ok(request) => Parsed::Json(request),
+        Err(err) => {
+            // Try to salvage the ID so the client can match the error to its request
+            let id = json::Json::from_str(line).ok()
+                .and_then(|json| json.find("id").and_then(|id| id.as_u64()))
+                .unwrap_or(0);
+
+            Parsed::Invalid(id, format!("Malformed request: {:?}", err))
+        }
+    }
+}
+
+/// The daemon's side of a single response.
+///
+/// In JSON mode every line written is framed as a `Reply`; in text mode writes go to the client untouched.
+/// Either way, `finish()` must be called once the op completes.
+pub struct Response<'a> {
+    client: &'a mut ClientStream,
+    id: Option<u64>,
+    partial: Vec<u8>,
+    error: Option<OpError>,
+}
+
+impl<'a> Response<'a> {
+    pub fn text(client: &'a mut ClientStream) -> Response<'a> {
+        Response::new(client, None)
+    }
+
+    pub fn json(client: &'a mut ClientStream, id: u64) -> Response<'a> {
+        Response::new(client, Some(id))
+    }
+
+    /// Continue a response that was left open, e.g. to stream lines to a follower.
+    pub fn resume(client: &'a mut ClientStream, id: Option<u64>) -> Response<'a> {
+        Response::new(client, id)
+    }
+
+    fn new(client: &'a mut ClientStream, id: Option<u64>) -> Response<'a> {
+        Response {
+            client: client,
+            id: id,
+            partial: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.id.is_some()
+    }
+
+    /// Mark the op as failed. In text mode, the message is written like any other output.
+    pub fn fail(&mut self, kind: ErrorKind, message: String) -> IoResult<()> {
+        if self.is_json() {
+            self.error = Some(OpError { kind: kind, message: message });
+            Ok(())
+        } else {
+            self.client.write_line(&*message)
+        }
+    }
+
+    /// Write the end-of-response marker (in JSON mode) and flush.
+    pub fn finish(mut self) -> IoResult<()> {
+        if let Some(id) = self.id {
+            if !self.partial.is_empty() {
+                try!(self.write_line_frame());
+            }
+
+            let outcome = Outcome {
+                ok: self.error.is_none(),
+                error: self.error.take(),
+            };
+
+            try!(self.write_reply(&Reply { id: id, line: None, end: Some(outcome) }));
+        }
+
+        self.client.flush()
+    }
+
+    fn write_line_frame(&mut self) -> IoResult<()> {
+        let line = String::from_utf8_lossy(&*self.partial).into_owned();
+        self.partial.clear();
+
+        let id = self.id.unwrap();
+        self.write_reply(&Reply { id: id, line: Some(line), end: None })
+    }
+
+    fn write_reply(&mut self, reply: &Reply) -> IoResult<()> {
+        self.client.write_line(&*json::encode(reply))
+    }
+}
+
+impl<'a> Writer for Response<'a> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        if !self.is_json() {
+            return self.client.write(buf);
+        }
+
+        for &byte in buf.iter() {
+            if byte == b'\n' {
+                try!(self.write_line_frame());
+            } else {
+                self.partial.push(byte);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.client.flush()
+    }
+}
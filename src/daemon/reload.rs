@@ -0,0 +1,246 @@
+//! Reloading the config, and working out what that changes for running servers.
+//!
+//! `reload-config --dry-run` only shows the plan. `reload-config` replaces the config but leaves running
+//! instances alone, as it always has. `reload-config --apply` also carries the plan out: removed servers are
+//! stopped, servers whose process would be started differently are restarted, other changes are applied to
+//! running instances in place, and servers newly listed in `start_servers` are started.
+
+use config::{self, Config};
+use super::{ClientResult, Daemon, ce, fail, stop_server, usage};
+use super::protocol::{ErrorKind, Response};
+
+use std::collections::HashMap;
+use std::io::{IoResult, stdio};
+
+enum Change {
+    Added,
+    Removed,
+    /// Fields the process is started with changed, so running instances must be restarted
+    Restart(Vec<&'static str>),
+    /// Only fields that can be applied to running instances changed
+    Update(Vec<&'static str>),
+}
+
+struct Plan {
+    /// By server name
+    changes: Vec<(String, Change)>,
+    /// Entries of `start_servers` that weren't there before
+    start: Vec<String>,
+}
+
+impl Plan {
+    fn new(old: &Config, new: &Config) -> Plan {
+        let mut changes = Vec::new();
+
+        for (name, config) in new.servers.iter() {
+            let change = match old.servers.get(name) {
+                None => Change::Added,
+                Some(old_config) => match old_config.changes(config) {
+                    (ref spawn, _) if !spawn.is_empty() => Change::Restart(spawn.clone()),
+                    (_, ref running) if !running.is_empty() => Change::Update(running.clone()),
+                    _ => continue,
+                },
+            };
+
+            changes.push((name.clone(), change));
+        }
+
+        for name in old.servers.keys().filter(|name| !new.servers.contains_key(*name)) {
+            changes.push((name.clone(), Change::Removed));
+        }
+
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Plan {
+            changes: changes,
+            start: new.start_servers.iter().filter(|server| !old.start_servers.contains(*server)).cloned().collect(),
+        }
+    }
+
+    /// The servers with the given kind of change
+    fn servers(&self, restart: bool, removed: bool, update: bool) -> Vec<String> {
+        self.changes.iter()
+            .filter(|&&(_, ref change)| match *change {
+                Change::Restart(_) => restart,
+                Change::Removed => removed,
+                Change::Update(_) => update,
+                Change::Added => false,
+            })
+            .map(|&(ref name, _)| name.clone())
+            .collect()
+    }
+}
+
+impl Daemon {
+    pub fn reload_config(&mut self, resp: &mut Response, args: Vec<String>) -> ClientResult<()> {
+        const USAGE: &'static str = "reload-config [--dry-run|--apply]";
+
+        let (dry_run, apply) = match args.get(0).map(|arg| &**arg) {
+            None => (false, false),
+            Some("--dry-run") => (true, false),
+            Some("--apply") => (false, true),
+            Some(_) => return usage(resp, USAGE),
+        };
+
+        try!(writeln!(resp, "Reloading config from {}...", self.config.path.display()).and_then(|_| resp.flush()));
+
+        let new_config = match Config::load(&self.config.path) {
+            Ok(config) => config,
+            Err(err) => {
+                return fail(resp, ErrorKind::Failed, format!("Failed to load config: {}", config::describe_error(&err)))
+            },
+        };
+
+        let plan = Plan::new(&self.config, &new_config);
+        try!(self.write_plan(&plan, resp));
+
+        if dry_run {
+            return ce(resp.write_line("Dry run, nothing was changed."));
+        }
+
+        if !apply {
+            self.replace_config(new_config);
+            return ce(resp.write_line(
+                "Config reloaded. Running instances were not changed, use `reload-config --apply` to apply the plan."
+            ));
+        }
+
+        self.apply_plan(plan, new_config, resp)
+    }
+
+    /// Reload the config for SIGHUP, like `reload-config` without options.
+    pub fn reload_on_signal(&mut self) {
+        let new_config = match Config::load(&self.config.path) {
+            Ok(config) => config,
+            Err(err) => return println!("Failed to reload config: {}", config::describe_error(&err)),
+        };
+
+        let plan = Plan::new(&self.config, &new_config);
+        if let Err(err) = self.write_plan(&plan, &mut stdio::stdout()) {
+            println!("Error showing the changes: {}", err);
+        }
+
+        self.replace_config(new_config);
+        println!("Config reloaded. Running instances were not changed.");
+    }
+
+    fn write_plan(&mut self, plan: &Plan, w: &mut Writer) -> IoResult<()> {
+        if plan.changes.is_empty() && plan.start.is_empty() {
+            return w.write_line("No changes to servers.");
+        }
+
+        try!(w.write_line("Changes (and what `--apply` does about them):"));
+
+        for &(ref server, ref change) in plan.changes.iter() {
+            let running = self.is_up(&**server);
+
+            try!(match *change {
+                Change::Added if plan.start.contains(server) => {
+                    writeln!(w, "  + \"{}\" added (will be started)", server)
+                },
+                Change::Added => writeln!(w, "  + \"{}\" added", server),
+                Change::Removed if running => writeln!(w, "  - \"{}\" removed (running, will be stopped)", server),
+                Change::Removed => writeln!(w, "  - \"{}\" removed", server),
+                Change::Restart(ref fields) if running => writeln!(
+                    w, "  ~ \"{}\" changed {} (running, will be restarted)", server, fields.connect(", ")
+                ),
+                Change::Update(ref fields) if running => writeln!(
+                    w, "  ~ \"{}\" changed {} (running, applied in place)", server, fields.connect(", ")
+                ),
+                Change::Restart(ref fields) | Change::Update(ref fields) => writeln!(
+                    w, "  ~ \"{}\" changed {}", server, fields.connect(", ")
+                ),
+            });
+        }
+
+        for server in plan.start.iter() {
+            let added = plan.changes.iter().any(|&(ref name, ref change)| match *change {
+                Change::Added => name == server,
+                _ => false,
+            });
+
+            if !added && !self.is_up(&**server) {
+                try!(writeln!(w, "  + \"{}\" added to `start_servers` (will be started)", server));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_plan(&mut self, plan: Plan, new_config: Config, resp: &mut Response) -> ClientResult<()> {
+        let removed = plan.servers(false, true, false);
+
+        // Stop dependents first, while the old config still describes them
+        let stopping = plan.servers(true, true, false);
+        let mut stop_order = self.config.start_order(&*stopping);
+        stop_order.retain(|server| stopping.contains(server));
+        stop_order.reverse();
+
+        let mut restarting = Vec::new();
+        // Still running, and still tracked so they can be stopped later
+        let mut not_stopped = Vec::new();
+
+        for server in stop_order.iter() {
+            if removed.contains(server) {
+                self.cancel_waiting(&**server);
+            }
+
+            for name in self.instances_of(&**server).into_iter() {
+                let running = self.is_running(&*name);
+
+                if running && !try!(stop_server(&*name, self.servers.get_mut(&name).unwrap(), resp)) {
+                    not_stopped.push(name);
+                } else if removed.contains(server) {
+                    self.servers.remove(&name);
+                } else if running && !restarting.contains(server) {
+                    // Kept, `start_all` respawns it with the new config and its history
+                    restarting.push(server.clone());
+                }
+
+                try!(resp.flush());
+            }
+        }
+
+        self.replace_config(new_config);
+
+        for server in plan.servers(false, false, true).iter() {
+            for name in self.instances_of(&**server).into_iter() {
+                if let Some(config) = self.config.instance_config(&*name) {
+                    self.servers.get_mut(&name).unwrap().reconfigure(config);
+                    try!(writeln!(resp, "Applied the new config to \"{}\".", name));
+                }
+            }
+        }
+
+        let mut starting = restarting;
+        starting.extend(plan.start.into_iter());
+
+        let failed = try!(self.start_all(&*starting, resp));
+
+        let mut problems = Vec::new();
+        if !not_stopped.is_empty() {
+            problems.push(format!("failed to stop {:?}", not_stopped));
+        }
+        if !failed.is_empty() {
+            problems.push(format!("failed to start {:?}", failed));
+        }
+
+        if problems.is_empty() {
+            ce(resp.write_line("Config reloaded and applied."))
+        } else {
+            fail(resp, ErrorKind::Failed, format!("Config reloaded, but {}", problems.connect(" and ")))
+        }
+    }
+
+    fn replace_config(&mut self, config: Config) {
+        self.config = config;
+        self.scheduler.reload(&self.config);
+
+        // A scale only lasts as long as the server is replicated
+        let config = &self.config;
+        let scales: HashMap<String, u32> = self.scales.drain()
+            .filter(|&(ref server, _)| config.servers.get(server).map_or(false, |config| config.instances.is_some()))
+            .collect();
+        self.scales = scales;
+    }
+}
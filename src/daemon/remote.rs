@@ -1,20 +1,29 @@
+use config::Config;
 use super::ClientStream;
+use super::protocol::{DETACH_ESCAPE, OpError, PROTOCOL_VERSION, Reply, Request};
 use util::ignore_timeout;
 
-use std::io::{self, IoResult};
+use rustc_serialize::json;
+
+use std::borrow::ToOwned;
+use std::io::{self, IoError, IoErrorKind, IoResult};
 use std::io::process::{Command, StdioContainer};
 use std::io::net::pipe::UnixStream;
 use std::io::timer::sleep;
-use std::io::util::copy;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
 use std::thread::Thread;
 use std::time::Duration;
 
+/// How many times to try connecting to a newly started daemon, 100ms apart
+const DAEMON_START_POLLS: u32 = 100;
+
 pub struct RemoteDaemon {
     stream: ClientStream,
     timeout_thread: TimeoutThread,  
+    next_id: u64,
 }
 
 impl RemoteDaemon {
@@ -26,61 +35,246 @@ impl RemoteDaemon {
         Ok(RemoteDaemon {
             stream: super::buffered(client_stream),
             timeout_thread: timeout_thread,
+            next_id: 1,
         })            
     }
         
-    pub fn connect_or_spawn(command: &str, socket: &str) -> IoResult<RemoteDaemon> {
+    /// Connect to the daemon, or start one using `config` if none is running.
+    pub fn connect_or_spawn(command: &str, config: &Config) -> IoResult<RemoteDaemon> {
+        let socket = &*config.socket_path;
+
         if let Ok(daemon) =  RemoteDaemon::connect(socket) {
             return Ok(daemon);    
         }
        
         io::stdio::println("Daemon not running! Starting...");         
-        try!(spawn_daemon(command));
-        sleep(Duration::seconds(1));
-        io::stdio::println("Daemon started.");
-        
-        RemoteDaemon::connect(socket)         
+        try!(spawn_daemon(command, &config.path));
+
+        // The daemon is ready once it accepts connections
+        for _ in 0..DAEMON_START_POLLS {
+            if let Ok(daemon) = RemoteDaemon::connect(socket) {
+                io::stdio::println("Daemon started.");
+                return Ok(daemon);
+            }
+
+            sleep(Duration::milliseconds(100));
+        }
+
+        Err(IoError {
+            kind: IoErrorKind::TimedOut,
+            desc: "Daemon did not start",
+            detail: Some(format!("Nothing is listening on {}, see {}", socket, config.daemon_log)),
+        })
     }
     
-    pub fn send_command(&mut self, command: &str) -> IoResult<()> {
-        self.timeout_thread.start();
-        try!(self.stream.write_line(command));
-        let ret = self.stream.flush();
-        self.timeout_thread.stop();
-        ret
-    }
+    /// Send an op to the daemon and copy its output to `w` until the end-of-response marker.
+    ///
+    /// Returns the error reported by the daemon, if the op failed.
+    /// Gives up if the daemon is silent for more than `max_timeouts` seconds.
+    pub fn request<W: Writer>(&mut self, op: &str, args: &[String], w: &mut W, max_timeouts: u32) 
+    -> IoResult<Result<(), OpError>> {
+        let id = try!(self.send_request(op, args));
 
-    pub fn write_response<W: Writer>(&mut self, w: &mut W, max_timeouts: u32) -> IoResult<()> {
         let mut timeouts = 0u32;
         self.stream.get_mut().set_timeout(Some(1000));
 
         loop {
             self.timeout_thread.start();
-            if try!(ignore_timeout(copy(&mut self.stream, w))).is_none() {
-                timeouts += 1;
+            let line = try!(ignore_timeout(self.stream.read_line()));
+            self.timeout_thread.stop();
+
+            let line = match line {
+                Some(line) => line,
+                None => {
+                    timeouts += 1;
+
+                    if timeouts > max_timeouts {
+                        return Err(timed_out());
+                    }
+
+                    continue;
+                },
+            };
 
-                if timeouts > max_timeouts {
-                    break;    
+            timeouts = 0;
+
+            if let Some(outcome) = try!(handle_reply(&*line, id, w)) {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    /// Run `follow` or `attach`, printing the server's output to `w` for as long as it runs.
+    ///
+    /// Lines from `input` are forwarded to the server when attached.
+    /// Typing `~.` on its own line detaches; closing stdin only means there's nothing more to forward.
+    /// Lines typed after the follow ended are left in `input` for the next reader.
+    pub fn follow<W: Writer>(&mut self, op: &str, args: &[String], w: &mut W, input: &StdinLines) 
+    -> IoResult<Result<(), OpError>> {
+        let id = try!(self.send_request(op, args));
+        let attach = op == "attach";
+        let mut detaching = false;
+        let mut input_closed = false;
+
+        // The server may be quiet for any amount of time, so only wait briefly before checking stdin
+        self.stream.get_mut().set_timeout(Some(100));
+
+        loop {
+            if let Some(line) = try!(ignore_timeout(self.stream.read_line())) {
+                if let Some(outcome) = try!(handle_reply(&*line, id, w)) {
+                    return Ok(outcome);
                 }
             }
 
-            self.timeout_thread.stop();
+            // After detaching, keep reading replies until the daemon ends the follow
+            while !detaching && !input_closed {
+                match input.try_read_line() {
+                    None => break,
+                    Some(Some(ref line)) if line.trim() != DETACH_ESCAPE => {
+                        if attach {
+                            try!(self.send_untracked("input", &[line.trim_right_matches('\n').to_owned()]));
+                        }
+                    },
+                    Some(Some(_)) => {
+                        detaching = true;
+                        try!(self.send_untracked("detach", &[]));
+                    },
+                    Some(None) => input_closed = true,
+                }
+            }
         }
+    }
+
+    fn send_request(&mut self, op: &str, args: &[String]) -> IoResult<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let request = Request {
+            version: PROTOCOL_VERSION,
+            id: id,
+            op: op.to_owned(),
+            args: args.to_vec(),
+        };
+
+        try!(self.send_line(&*json::encode(&request)));
+        Ok(id)
+    }
+
+    /// Requests made while following don't get their own replies, so the ID doesn't matter.
+    fn send_untracked(&mut self, op: &str, args: &[String]) -> IoResult<()> {
+        let request = Request {
+            version: PROTOCOL_VERSION,
+            id: 0,
+            op: op.to_owned(),
+            args: args.to_vec(),
+        };
+
+        self.send_line(&*json::encode(&request))
+    }
+
+    fn send_line(&mut self, line: &str) -> IoResult<()> {
+        self.timeout_thread.start();
+        try!(self.stream.write_line(line));
+        let ret = self.stream.flush();
         self.timeout_thread.stop();
+        ret
+    }
+}
+
+/// Write out a reply to request `id`, returning the op's outcome if it was the last one.
+fn handle_reply<W: Writer>(line: &str, id: u64, w: &mut W) -> IoResult<Option<Result<(), OpError>>> {
+    let reply = match json::decode::<Reply>(line.trim()) {
+        Ok(reply) => reply,
+        Err(err) => return Err(bad_reply(format!("{:?}", err))),
+    };
+
+    // Stale reply to an earlier request that we gave up on
+    if reply.id != id { return Ok(None); }
+
+    if let Some(line) = reply.line {
+        try!(w.write_line(&*line).and_then(|_| w.flush()));
+    }
+
+    Ok(reply.end.map(|outcome| match outcome.error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }))
+}
+
+/// Lines of stdin, read by a thread of its own so `follow` can wait on the daemon and stdin at once.
+///
+/// Every line goes through the channel to whoever reads next, the prompt or a follow, so none are lost
+/// when a follow ends while the thread is waiting for input.
+pub struct StdinLines {
+    lines: Receiver<String>,
+}
+
+impl StdinLines {
+    pub fn new() -> StdinLines {
+        let (tx, lines) = channel();
 
-        w.flush()
+        Thread::spawn(move || {
+            let mut stdin = io::stdio::stdin();
+
+            while let Ok(line) = stdin.read_line() {
+                if tx.send(line).is_err() { break; }
+            }
+        });
+
+        StdinLines { lines: lines }
+    }
+
+    /// The next line, or `None` at EOF.
+    pub fn read_line(&self) -> Option<String> {
+        self.lines.recv().ok()
+    }
+
+    /// `None` if no line is waiting, `Some(None)` at EOF.
+    fn try_read_line(&self) -> Option<Option<String>> {
+        match self.lines.try_recv() {
+            Ok(line) => Some(Some(line)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(None),
+        }
     }
 }
 
-fn spawn_daemon(command: &str) -> IoResult<()> {
+fn timed_out() -> IoError {
+    IoError {
+        kind: IoErrorKind::TimedOut,
+        desc: "Daemon stopped responding",
+        detail: None,
+    }
+}
+
+fn bad_reply(detail: String) -> IoError {
+    IoError {
+        kind: IoErrorKind::InvalidInput,
+        desc: "Malformed reply from daemon",
+        detail: Some(detail),
+    }
+}
+
+/// Start a daemon and wait for it to fork into the background. Errors from before it forks are printed
+/// to our stderr.
+fn spawn_daemon(command: &str, config_path: &Path) -> IoResult<()> {
     let mut command = Command::new(command);
-    command.arg("start-daemon")
+    command.arg("--config").arg(config_path).arg("start-daemon")
         .stdin(StdioContainer::Ignored)
         .stdout(StdioContainer::Ignored)
-        .stderr(StdioContainer::Ignored)
-        .detached();
+        .stderr(StdioContainer::InheritFd(2));
 
-    Ok(try!(command.spawn()).forget()) 
+    let status = try!(command.status());
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(IoError {
+            kind: IoErrorKind::OtherIoError,
+            desc: "Daemon failed to start",
+            detail: Some(format!("start-daemon {}", status)),
+        })
+    }
 }
 
 struct TimeoutThread {
@@ -0,0 +1,126 @@
+//! Servers with `instances` set run as several replicas, named `<server>@1` to `<server>@N`.
+//!
+//! Ops given a server's name apply to all of its replicas, or to one when given an instance name.
+//! `scale` changes how many replicas run until the daemon exits.
+
+use config::{INSTANCE_SEPARATOR, split_instance};
+use super::{ClientResult, Daemon, ce, fail, no_config, not_running, stop_server, usage};
+use super::protocol::{ErrorKind, Response};
+use super::restart::RestartState;
+
+impl Daemon {
+    /// How many replicas of `server` should run
+    fn instance_count(&self, server: &str) -> u32 {
+        self.scales.get(server).cloned()
+            .or_else(|| self.config.servers.get(server).and_then(|config| config.instances))
+            .unwrap_or(1)
+    }
+
+    /// The instances `start` brings up for `server`: its replicas, or just itself if it isn't replicated.
+    pub fn instance_names(&self, server: &str) -> Vec<String> {
+        match self.config.servers.get(server) {
+            Some(config) if config.instances.is_some() => {
+                (1..self.instance_count(server) + 1)
+                    .map(|instance| format!("{}{}{}", server, INSTANCE_SEPARATOR, instance))
+                    .collect()
+            },
+            _ => vec![server.to_string()],
+        }
+    }
+
+    /// Whether `name` is within its server's current count of replicas, or isn't an instance name.
+    pub fn in_scale(&self, name: &str) -> bool {
+        match split_instance(name) {
+            (server, Some(instance)) => instance <= self.instance_count(server),
+            (_, None) => true,
+        }
+    }
+
+    /// The existing instances `name` refers to: itself, or all replicas of a server in order.
+    pub fn instances_of(&self, name: &str) -> Vec<String> {
+        if self.servers.contains_key(name) {
+            return vec![name.to_string()];
+        }
+
+        let mut replicas: Vec<(u32, String)> = self.servers.keys()
+            .filter_map(|key| match split_instance(&**key) {
+                (server, Some(instance)) if server == name => Some((instance, key.clone())),
+                _ => None,
+            })
+            .collect();
+        replicas.sort();
+
+        replicas.into_iter().map(|(_, key)| key).collect()
+    }
+
+    /// Whether any instance of `server` has a live process
+    pub fn is_up(&mut self, server: &str) -> bool {
+        self.instances_of(server).iter().any(|instance| self.is_running(&**instance))
+    }
+
+    /// Whether a stop would do anything to `name`: it's running or waiting to be restarted.
+    pub fn is_active(&mut self, name: &str) -> bool {
+        self.is_running(name) || match self.servers.get(name).map(|instance| instance.restart_state()) {
+            Some(RestartState::Pending(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// For ops that work on one instance: `name` if it's an instance, or the only replica of a server.
+    pub fn resolve_one(&self, resp: &mut Response, name: &str) -> ClientResult<Option<String>> {
+        let mut instances = self.instances_of(name);
+
+        match instances.len() {
+            0 => not_running(resp, name).map(|_| None),
+            1 => Ok(instances.pop()),
+            _ => fail(
+                resp, ErrorKind::Usage, format!("\"{}\" has several instances, pick one of {:?}", name, instances)
+            ).map(|_| None),
+        }
+    }
+
+    pub fn scale_server(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
+        const USAGE: &'static str = "scale <server> <count>";
+
+        let count = match args.get(1).and_then(|count| count.parse::<u32>()) {
+            Some(count) => count,
+            None => return usage(resp, USAGE),
+        };
+
+        let server = args.remove(0);
+
+        match self.config.servers.get(&server) {
+            Some(config) if config.instances.is_some() => (),
+            Some(_) => return fail(
+                resp, ErrorKind::Failed, format!("\"{}\" isn't replicated, set `instances` in its config", server)
+            ),
+            None => return no_config(resp, &*server),
+        }
+
+        self.scales.insert(server.clone(), count);
+
+        // Stop the highest numbered replicas first
+        for name in self.instances_of(&*server).into_iter().rev() {
+            if split_instance(&*name).1.map_or(false, |instance| instance > count) {
+                self.cancel_waiting(&*name);
+
+                // Only forgotten once it's stopped, a failed stop leaves it tracked and running
+                if !try!(stop_server(&*name, self.servers.get_mut(&name).unwrap(), resp)) {
+                    return Ok(());
+                }
+
+                self.servers.remove(&name);
+            }
+        }
+
+        if count > 0 {
+            let failed = try!(self.start_all(&[server.clone()], resp));
+
+            if !failed.is_empty() {
+                return fail(resp, ErrorKind::Failed, format!("Failed to start {:?}", failed));
+            }
+        }
+
+        ce(writeln!(resp, "Scaled \"{}\" to {} instances.", server, count))
+    }
+}
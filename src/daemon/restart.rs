@@ -0,0 +1,163 @@
+//! Deciding whether and when to restart a server that has exited.
+
+use config::{RestartPolicy, ServerConfig};
+use util::precise_time_ms;
+
+use std::cmp::min;
+use std::collections::RingBuf;
+use std::io::process::ProcessExit;
+
+pub const DEFAULT_BACKOFF: u64 = 1;
+pub const DEFAULT_BACKOFF_MAX: u64 = 60;
+pub const DEFAULT_MAX_RESTARTS: u32 = 5;
+pub const DEFAULT_WINDOW: u64 = 60;
+
+pub struct RestartSettings {
+    policy: RestartPolicy,
+    success_codes: Vec<isize>,
+    /// All times in ms
+    backoff: u64,
+    backoff_max: u64,
+    max_restarts: u32,
+    window: u64,
+}
+
+impl RestartSettings {
+    /// The restart policy must already have been validated, which happens at load.
+    pub fn for_config(config: &ServerConfig) -> RestartSettings {
+        RestartSettings {
+            policy: config.restart_policy().unwrap_or(RestartPolicy::Never),
+            success_codes: config.success_exit_codes.clone().unwrap_or_else(|| vec![0]),
+            backoff: config.restart_backoff.unwrap_or(DEFAULT_BACKOFF) * 1000,
+            backoff_max: config.restart_backoff_max.unwrap_or(DEFAULT_BACKOFF_MAX) * 1000,
+            max_restarts: config.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS),
+            window: config.restart_window.unwrap_or(DEFAULT_WINDOW) * 1000,
+        }
+    }
+
+    fn is_failure(&self, exit: Option<&ProcessExit>) -> bool {
+        match exit {
+            Some(&ProcessExit::ExitStatus(code)) => !self.success_codes.contains(&code),
+            // Killed by a signal, or we couldn't tell
+            _ => true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum RestartState {
+    /// The server is running, or has exited and not been looked at yet
+    Idle,
+    /// The server will be restarted at the given time (from `precise_time_ms()`)
+    Pending(u64),
+    /// The server restarted too often within the window
+    GaveUp,
+    /// The policy says the server stays down
+    NotRestarting,
+    /// The server was stopped on request and stays down until started again
+    Stopped,
+}
+
+/// Carried across restarts of the same server to implement backoff and crash-loop detection.
+pub struct RestartTracker {
+    settings: RestartSettings,
+    /// When the recent restarts happened
+    recent: RingBuf<u64>,
+    next_backoff: u64,
+    state: RestartState,
+    total: u32,
+}
+
+impl RestartTracker {
+    pub fn new(settings: RestartSettings) -> RestartTracker {
+        RestartTracker {
+            next_backoff: settings.backoff,
+            settings: settings,
+            recent: RingBuf::new(),
+            state: RestartState::Idle,
+            total: 0,
+        }
+    }
+
+    pub fn state(&self) -> RestartState {
+        self.state
+    }
+
+    /// The total number of automatic restarts.
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    /// How many restarts happened within the window.
+    pub fn recent(&self) -> usize {
+        self.recent.len()
+    }
+
+    /// Whether the server could still be brought back automatically.
+    pub fn may_restart(&self) -> bool {
+        match self.state {
+            RestartState::GaveUp | RestartState::NotRestarting | RestartState::Stopped => false,
+            _ => self.settings.policy != RestartPolicy::Never,
+        }
+    }
+
+    /// Decide what to do about a server that exited after running for `uptime` ms.
+    /// `force` restarts regardless of the policy, e.g. for hung servers, but still counts towards giving up.
+    pub fn schedule(&mut self, exit: Option<&ProcessExit>, uptime: u64, force: bool) -> RestartState {
+        let wanted = force || match self.settings.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => self.settings.is_failure(exit),
+            RestartPolicy::Always => true,
+        };
+
+        if !wanted {
+            self.state = RestartState::NotRestarting;
+            return self.state;
+        }
+
+        let now = precise_time_ms();
+
+        // It ran long enough to count as having recovered
+        if uptime >= self.settings.window {
+            self.next_backoff = self.settings.backoff;
+        }
+
+        while self.recent.front().map_or(false, |&time| now - time > self.settings.window) {
+            self.recent.pop_front();
+        }
+
+        if self.recent.len() as u32 >= self.settings.max_restarts {
+            self.state = RestartState::GaveUp;
+            return self.state;
+        }
+
+        self.state = RestartState::Pending(now + self.next_backoff);
+        self.next_backoff = min(self.next_backoff * 2, self.settings.backoff_max);
+
+        self.state
+    }
+
+    /// Whether `exit` counts as a failure of the server.
+    pub fn is_failure(&self, exit: Option<&ProcessExit>) -> bool {
+        self.settings.is_failure(exit)
+    }
+
+    /// Use the settings from a reloaded config, keeping the restart history.
+    pub fn reconfigure(&mut self, settings: RestartSettings) {
+        self.next_backoff = min(self.next_backoff, settings.backoff_max);
+        self.settings = settings;
+    }
+
+    /// Record that the server was stopped on request, cancelling any pending restart.
+    pub fn stopped(&mut self) {
+        self.state = RestartState::Stopped;
+    }
+
+    /// Record that the server was restarted, taking the settings from its possibly reloaded config.
+    pub fn restarted(&mut self, settings: RestartSettings) {
+        self.recent.push_back(precise_time_ms());
+        self.total += 1;
+        self.settings = settings;
+        self.state = RestartState::Idle;
+    }
+}
@@ -0,0 +1,350 @@
+//! Actions run on a server at times given by cron expressions, checked from the daemon's main loop.
+
+use config::{Config, ScheduleConfig};
+use cron::Cron;
+use super::{ClientResult, Daemon, ce, describe_exit, fail, no_config, usage};
+use super::exit::FormatExit;
+use super::hooks::{self, Event, run_hook};
+use super::protocol::{ErrorKind, Response};
+
+use time::{self, Timespec, Tm};
+
+use std::fmt;
+use std::io::{IoResult, stdio};
+use std::thread::Thread;
+
+/// Minutes missed while the daemon was busy are caught up on, unless more than this many were missed,
+/// e.g. because the clock changed.
+const MAX_CATCH_UP: i64 = 5;
+
+#[derive(Clone)]
+pub enum Action {
+    Start,
+    Stop,
+    Restart,
+    /// Send a line to the server's input
+    Send(String),
+    /// Run a command and its arguments, with a timeout in seconds
+    Hook(Vec<String>, u64),
+}
+
+impl Action {
+    pub fn from_config(config: &ScheduleConfig) -> Result<Action, String> {
+        try!(config.validate());
+
+        Ok(match &*config.action {
+            "start" => Action::Start,
+            "stop" => Action::Stop,
+            "restart" => Action::Restart,
+            "send" => Action::Send(config.command.clone().unwrap()),
+            "hook" => Action::Hook(config.hook.clone().unwrap(), config.timeout.unwrap_or(hooks::DEFAULT_TIMEOUT)),
+            _ => unreachable!(),
+        })
+    }
+}
+
+impl fmt::String for Action {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Action::Start => fmt.pad("start"),
+            Action::Stop => fmt.pad("stop"),
+            Action::Restart => fmt.pad("restart"),
+            Action::Send(ref command) => fmt.write_fmt(format_args!("send {:?}", command)),
+            Action::Hook(ref command, _) => fmt.write_fmt(format_args!("hook {:?}", command)),
+        }
+    }
+}
+
+pub struct Job {
+    pub server: String,
+    pub expr: String,
+    pub cron: Cron,
+    pub action: Action,
+    pub last_run: Option<Tm>,
+}
+
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    /// The last minute (since the epoch) that jobs were checked for
+    last_minute: i64,
+}
+
+impl Scheduler {
+    /// The schedules must already have been validated, which happens at load.
+    pub fn new(config: &Config) -> Scheduler {
+        Scheduler {
+            jobs: jobs_for_config(config),
+            last_minute: time::get_time().sec / 60,
+        }
+    }
+
+    /// Replace the jobs with those from a reloaded config, keeping when unchanged ones last ran.
+    pub fn reload(&mut self, config: &Config) {
+        let mut jobs = jobs_for_config(config);
+
+        for job in jobs.iter_mut() {
+            job.last_run = self.jobs.iter()
+                .find(|old| old.server == job.server && old.expr == job.expr)
+                .and_then(|old| old.last_run.clone());
+        }
+
+        self.jobs = jobs;
+    }
+
+    /// The indexes of the jobs that are due since the last call.
+    pub fn due(&mut self) -> Vec<usize> {
+        self.due_until(time::get_time().sec / 60, time::at)
+    }
+
+    /// The jobs due in the minutes since the last call up to `minute` (since the epoch),
+    /// with `at` giving the time they're matched against.
+    fn due_until(&mut self, minute: i64, at: fn(Timespec) -> Tm) -> Vec<usize> {
+        if minute <= self.last_minute {
+            return Vec::new();
+        }
+
+        let from = if minute - self.last_minute > MAX_CATCH_UP { minute } else { self.last_minute + 1 };
+        self.last_minute = minute;
+
+        let mut due = Vec::new();
+
+        for minute in from..minute + 1 {
+            let tm = at(Timespec::new(minute * 60, 0));
+
+            for (index, job) in self.jobs.iter().enumerate() {
+                if job.cron.matches(&tm) && !due.contains(&index) {
+                    due.push(index);
+                }
+            }
+        }
+
+        due
+    }
+
+    /// The jobs of `server`, numbered from 1 in config order, or of all servers.
+    fn jobs_of(&self, server: Option<&str>) -> Vec<(usize, usize, &Job)> {
+        let mut numbered = Vec::new();
+
+        for (index, job) in self.jobs.iter().enumerate() {
+            if server.map_or(true, |server| server == &*job.server) {
+                let number = self.jobs[..index].iter().filter(|other| other.server == job.server).count() + 1;
+                numbered.push((index, number, job));
+            }
+        }
+
+        numbered
+    }
+}
+
+fn jobs_for_config(config: &Config) -> Vec<Job> {
+    let mut servers: Vec<_> = config.servers.iter().collect();
+    servers.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut jobs = Vec::new();
+
+    for (server, server_config) in servers.into_iter() {
+        for schedule in server_config.schedule.iter().flat_map(|schedules| schedules.iter()) {
+            jobs.push(Job {
+                server: server.clone(),
+                expr: schedule.cron.clone(),
+                cron: Cron::parse(&*schedule.cron).ok().expect("Schedule was not validated"),
+                action: Action::from_config(schedule).ok().expect("Schedule was not validated"),
+                last_run: None,
+            });
+        }
+    }
+
+    jobs
+}
+
+impl Daemon {
+    /// Run any jobs that are due, reporting to the daemon's output.
+    pub fn run_scheduled(&mut self) {
+        for index in self.scheduler.due().into_iter() {
+            if let Err(err) = self.run_job(index, &mut stdio::stdout()) {
+                println!("Error running scheduled job: {}", err);
+            }
+        }
+    }
+
+    fn run_job(&mut self, index: usize, w: &mut Writer) -> IoResult<()> {
+        let (server, action) = {
+            let job = &mut self.scheduler.jobs[index];
+            job.last_run = Some(time::now());
+            (job.server.clone(), job.action.clone())
+        };
+
+        try!(writeln!(w, "Running scheduled {} of \"{}\"...", action, server));
+
+        match action {
+            Action::Start => {
+                let instances = self.instance_names(&*server);
+
+                if instances.is_empty() {
+                    writeln!(w, "\"{}\" is scaled to 0 instances, nothing to start.", server)
+                } else if instances.iter().all(|instance| self.is_running(&**instance)) {
+                    writeln!(w, "\"{}\" is already running.", server)
+                } else {
+                    self.start_all(&[server], w).map(|_| ())
+                }
+            },
+            Action::Stop => self.stop_scheduled(&*server, w),
+            Action::Restart => {
+                try!(self.stop_scheduled(&*server, w));
+                self.start_all(&[server], w).map(|_| ())
+            },
+            Action::Send(command) => {
+                let mut sent = false;
+
+                for name in self.instances_of(&*server).iter() {
+                    if self.is_running(&**name) {
+                        try!(self.servers.get_mut(name).unwrap().send_command(&*command));
+                        sent = true;
+                    }
+                }
+
+                if sent {
+                    Ok(())
+                } else {
+                    writeln!(w, "\"{}\" is not running, not sending {:?}.", server, command)
+                }
+            },
+            Action::Hook(command, timeout) => {
+                let config = match self.config.servers.get(&server) {
+                    Some(config) => config.clone(),
+                    None => return writeln!(w, "No configuration for \"{}\", not running the hook.", server),
+                };
+
+                // Hooks can take a while, e.g. backups, so don't hold up the daemon
+                Thread::spawn(move || {
+                    let vars = vec![("SHEPHERD_EVENT".to_string(), Event::Schedule.name().to_string())];
+
+                    match run_hook(&*server, &config, &*command, timeout, vars) {
+                        Ok(exit) => println!("Hook {:?} of \"{}\" {}", command, server, FormatExit(&exit)),
+                        Err(err) => println!("Error running hook {:?} of \"{}\": {}", command, server, err),
+                    }
+                });
+
+                Ok(())
+            },
+        }
+    }
+
+    fn stop_scheduled(&mut self, server: &str, w: &mut Writer) -> IoResult<()> {
+        let instances: Vec<String> = self.instances_of(server).into_iter()
+            .filter(|name| self.is_active(&**name))
+            .collect();
+
+        if instances.is_empty() {
+            return writeln!(w, "\"{}\" was not running.", server);
+        }
+
+        // Kept so their history carries over to the next start
+        for name in instances.into_iter() {
+            let instance = self.servers.get_mut(&name).unwrap();
+            let status = try!(instance.stop());
+            try!(writeln!(w, "\"{}\" stopped. Status: {}{}", name, status, describe_exit(instance)));
+        }
+
+        Ok(())
+    }
+
+    pub fn schedule_op(&mut self, resp: &mut Response, mut args: Vec<String>) -> ClientResult<()> {
+        const USAGE: &'static str = "schedule list [server] | schedule run-now <server> <number>";
+
+        if args.is_empty() {
+            return usage(resp, USAGE);
+        }
+
+        match &*args.remove(0) {
+            "list" => {
+                let jobs = self.scheduler.jobs_of(args.get(0).map(|s| &**s));
+
+                if jobs.is_empty() {
+                    return ce(resp.write_line("No schedules."));
+                }
+
+                try!(resp.write_line("Schedules:"));
+
+                for &(_, number, job) in jobs.iter() {
+                    let last_run = job.last_run.as_ref()
+                        .map_or("never run".to_string(), |tm| format!("last run {}", tm.rfc3339()));
+                    try!(writeln!(resp, "\"{}\" #{}: {} {} ({})", job.server, number, job.expr, job.action, last_run));
+                }
+
+                Ok(())
+            },
+            "run-now" => {
+                let (server, number) = match (args.get(0), args.get(1).and_then(|n| n.parse::<usize>())) {
+                    (Some(server), Some(number)) => (server.clone(), number),
+                    _ => return usage(resp, USAGE),
+                };
+
+                if !self.config.servers.contains_key(&server) {
+                    return no_config(resp, &*server);
+                }
+
+                let index = self.scheduler.jobs_of(Some(&*server)).iter()
+                    .find(|&&(_, n, _)| n == number)
+                    .map(|&(index, _, _)| index);
+
+                match index {
+                    Some(index) => ce(self.run_job(index, resp)),
+                    None => fail(resp, ErrorKind::Failed, format!("\"{}\" has no schedule #{}", server, number)),
+                }
+            },
+            _ => usage(resp, USAGE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, Job, Scheduler};
+    use cron::Cron;
+
+    use time;
+
+    /// 2015-01-01 00:00 UTC, in minutes since the epoch
+    const NEW_YEAR: i64 = 1420070400 / 60;
+
+    fn scheduler(exprs: &[&str], last_minute: i64) -> Scheduler {
+        Scheduler {
+            jobs: exprs.iter().map(|expr| Job {
+                server: "web".to_string(),
+                expr: expr.to_string(),
+                cron: Cron::parse(*expr).unwrap(),
+                action: Action::Restart,
+                last_run: None,
+            }).collect(),
+            last_minute: last_minute,
+        }
+    }
+
+    #[test]
+    fn due_once_per_minute() {
+        let mut scheduler = scheduler(&["* * * * *", "0 * * * *"], NEW_YEAR - 1);
+
+        assert_eq!(scheduler.due_until(NEW_YEAR, time::at_utc), vec![0, 1]);
+        assert_eq!(scheduler.due_until(NEW_YEAR, time::at_utc), Vec::<usize>::new());
+        assert_eq!(scheduler.due_until(NEW_YEAR + 1, time::at_utc), vec![0]);
+    }
+
+    #[test]
+    fn catches_up_on_missed_minutes() {
+        // Busy from 23:58 until 00:02, the hourly job still runs once
+        let mut scheduler = scheduler(&["0 * * * *", "* * * * *"], NEW_YEAR - 2);
+
+        assert_eq!(scheduler.due_until(NEW_YEAR + 2, time::at_utc), vec![1, 0]);
+        assert_eq!(scheduler.last_minute, NEW_YEAR + 2);
+    }
+
+    #[test]
+    fn skips_long_gaps() {
+        // Too many minutes missed, e.g. the clock jumped, so only the current one counts
+        let mut scheduler = scheduler(&["0 * * * *"], NEW_YEAR - 60);
+
+        assert_eq!(scheduler.due_until(NEW_YEAR + 1, time::at_utc), Vec::<usize>::new());
+        assert_eq!(scheduler.last_minute, NEW_YEAR + 1);
+    }
+}
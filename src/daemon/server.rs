@@ -1,70 +1,390 @@
-use config::ServerConfig;
-use util::{FormatBytes, FormatTime, precise_time_ms};
+use config::{ServerConfig, split_instance};
+use super::cgroup::Cgroup;
+use super::env::{build_env, interpolate_command};
+use super::exit::{ExitHistory, ExitRecord, FormatExit};
+use super::hooks::{Event, run_event, spawn_event};
+use super::log::{LogFile, LogSettings};
+use super::probe::{HealthCheck, LogWatch, Readiness, ReadyCheck};
+use super::restart::{RestartSettings, RestartState, RestartTracker};
+use super::shim::{ShimOptions, check_exec};
+use super::stats::{self, Sample, Sampler};
+use super::tree::{ProcessEntry, ProcessTable, SIGKILL, SIGTERM, signal_group};
+use util::{FormatBytes, FormatTime, precise_time_ms, ticks_per_second};
 
 use std::borrow::ToOwned;
+use std::cmp::min;
 use std::fmt;
-use std::io::{BufferedReader, File, IoResult};
-use std::io::process::{Command, Process};
+use std::iter::AdditiveIterator;
+use std::mem;
+use std::io::{BufferedReader, File, IoError, IoErrorKind, IoResult};
+use std::io::process::{Command, Process, ProcessExit, StdioContainer};
 use std::io::pipe::PipeStream;
 use std::os;
-use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use std::thread::Thread;
 
 pub const STOP_TIMEOUT: Option<u64> = Some(10000);
 
 pub struct Server {
+   name: String,
    process: Process,
    config: ServerConfig,
-   lines: Receiver<String>,
-   log: Vec<String>, 
+   lines: Receiver<LogLine>,
+   log: Vec<LogLine>, 
+   /// Total lines received, including those truncated from `log`
+   line_count: u64,
+   ready_check: Option<ReadyCheck>,
+   health_check: Option<HealthCheck>,
+   /// From `precise_time_ms()`
+   started_at: u64,
+   /// Set once the process has been reaped
+   exit: Option<ProcessExit>,
+   /// Carried over when the server is respawned
+   exits: ExitHistory,
+   restarts: RestartTracker,
+   cgroup: Option<Cgroup>,
+   /// Carried over when the server is respawned
+   stats: Sampler,
+   /// Shared with the threads reading the process's output
+   log_file: Option<Arc<Mutex<LogFile>>>,
+   /// The checks' log patterns, matched by the threads reading the process's output
+   watches: Arc<Mutex<Vec<LogWatch>>>,
 }
 
 impl Server {
-    pub fn spawn(config: ServerConfig) -> IoResult<Server> {
+    pub fn spawn(name: &str, config: ServerConfig) -> IoResult<Server> {
         let ref dir = Path::new(&*config.dir);
-        let mut command = Command::new(&*config.command);
+        let env = try!(build_env(&config, split_instance(name).1));
+        let (program, args) = try!(interpolate_command(&config, &env));
+
+        try!(run_event(Event::PreStart, name, &config, None, None));
+
+        let cgroup = match config.limits.as_ref().map(|limits| Cgroup::for_server(name, limits)) {
+            Some(Ok(cgroup)) => cgroup,
+            Some(Err(err)) => {
+                println!("Not applying cgroup limits to \"{}\": {}", name, err);
+                None
+            },
+            None => None,
+        };
+
+        let shim = try!(ShimOptions::for_config(&config, cgroup.as_ref()));
+
+        let mut command = Command::new(&*program);
         
-        for arg in config.args.iter() {
+        for arg in args.iter() {
             command.arg(arg);    
         }
 
-        command.cwd(dir);
-        
         println!("Starting process: {}", command);
 
-        let process = try!(command.spawn());
+        if let Some(ref shim) = shim {
+            command = try!(shim.command(&*program, &*args));
+        }
+
+        let env: Vec<(String, String)> = env.into_iter().collect();
+
+        // Start a new session, so the server and everything it starts share a process group
+        command.cwd(dir)
+            .detached()
+            .env_set_all(&*env)
+            .stdout(StdioContainer::CreatePipe(false, true))
+            .stderr(StdioContainer::CreatePipe(false, true));
+
+        let log_file = match LogSettings::for_config(&config) {
+            Some(settings) => Some(try!(LogFile::open(settings))),
+            None => None,
+        };
+
+        let mut process = try!(command.spawn());
 
-        let lines = read_lines_threaded(process.stdout.clone().unwrap());
+        if shim.is_some() {
+            try!(check_exec(&mut process));
+        }
+
+        spawn_event(Event::PostStart, name, &config, Some(process.id()), None);
+
+        let restarts = RestartTracker::new(RestartSettings::for_config(&config));
+        let stats = Sampler::new(
+            config.stats_interval.unwrap_or(stats::DEFAULT_INTERVAL),
+            config.stats_history.unwrap_or(stats::DEFAULT_HISTORY)
+        );
+        let ready_check = config.ready.as_ref().map(ReadyCheck::new);
+        let health_check = config.health.as_ref().map(HealthCheck::new);
+
+        let log_file = log_file.map(|mut log_file| {
+            let _ = log_file.write_line(&*format!("--- shepherd: started process {} ---", process.id()));
+            Arc::new(Mutex::new(log_file))
+        });
+
+        let watches = Arc::new(Mutex::new(log_watches(&ready_check, &health_check)));
+
+        // Both streams feed the same channel so lines stay in the order they were read
+        let (tx, lines) = sync_channel(MAX_LINES);
+        read_lines_threaded(
+            process.stdout.clone().unwrap(), Stream::Stdout, tx.clone(), log_file.clone(), watches.clone()
+        );
+        read_lines_threaded(process.stderr.clone().unwrap(), Stream::Stderr, tx, log_file.clone(), watches.clone());
 
         Ok(Server {
+            name: name.to_string(),
             process: process,
             config: config,
             lines: lines,
             log: Vec::new(),
+            line_count: 0,
+            ready_check: ready_check,
+            health_check: health_check,
+            started_at: precise_time_ms(),
+            exit: None,
+            exits: ExitHistory::new(),
+            restarts: restarts,
+            cgroup: cgroup,
+            stats: stats,
+            log_file: log_file,
+            watches: watches,
         })             
     }
 
+    /// Start a new process for this server. Automatic restarts count towards the restart policy's limits,
+    /// manual ones reset them. Failed automatic restarts count too.
+    pub fn respawn(&mut self, config: ServerConfig, automatic: bool) -> IoResult<()> {
+        let settings = RestartSettings::for_config(&config);
+
+        let mut new = match Server::spawn(&*self.name, config) {
+            Ok(new) => new,
+            Err(err) => {
+                if automatic {
+                    self.restarts.restarted(settings);
+                }
+                return Err(err);
+            },
+        };
+        mem::swap(&mut new.exits, &mut self.exits);
+        mem::swap(&mut new.stats, &mut self.stats);
+
+        if automatic {
+            // Swap the histories so the old one is carried over
+            mem::swap(&mut new.restarts, &mut self.restarts);
+            new.restarts.restarted(settings);
+        }
+
+        *self = new;
+        Ok(())
+    }
+
+    /// Use a reloaded config whose changes don't need a new process. A changed health check starts over.
+    pub fn reconfigure(&mut self, config: ServerConfig) {
+        self.restarts.reconfigure(RestartSettings::for_config(&config));
+
+        if config.health != self.config.health {
+            self.health_check = config.health.as_ref().map(HealthCheck::new);
+            *self.watches.lock().unwrap() = log_watches(&self.ready_check, &self.health_check);
+        }
+
+        self.config = config;
+    }
+
     pub fn is_alive(&mut self) -> bool {
-        self.process.signal(0).is_ok()    
+        self.poll_exit().is_none()
+    }
+
+    /// Reap the process if it has exited, without blocking.
+    pub fn poll_exit(&mut self) -> Option<&ProcessExit> {
+        if self.exit.is_none() {
+            self.process.set_timeout(Some(0));
+
+            if let Ok(exit) = self.process.wait() {
+                self.record_exit(exit);
+            }
+
+            self.process.set_timeout(None);
+        }
+
+        self.exit.as_ref()
+    }
+
+    fn record_exit(&mut self, exit: ProcessExit) {
+        let uptime = (precise_time_ms() - self.started_at) / 1000;
+        let limit_hits = self.cgroup.as_ref().and_then(|cgroup| cgroup.limit_hits());
+        self.exits.push(ExitRecord::new(self.pid(), exit.clone(), uptime, limit_hits));
+        self.exit = Some(exit);
+    }
+
+    /// The most recent exit of this server, from this process or an earlier one.
+    pub fn last_exit(&self) -> Option<&ExitRecord> {
+        self.exits.last()
+    }
+
+    /// List the remembered exits, newest first.
+    pub fn write_exit_history(&self, w: &mut Writer) -> IoResult<()> {
+        if self.exits.is_empty() {
+            return Ok(());
+        }
+
+        try!(w.write_line("Exit history (newest first):"));
+        for record in self.exits.iter().rev() {
+            try!(writeln!(w, "    {}", record));
+        }
+
+        Ok(())
+    }
+
+    pub fn restart_state(&self) -> RestartState {
+        self.restarts.state()
+    }
+
+    /// Whether the server may be restarted automatically if it isn't running.
+    pub fn may_restart(&self) -> bool {
+        self.restarts.may_restart()
+    }
+
+    /// Decide whether and when to restart the exited process.
+    /// `force` restarts it regardless of the restart policy.
+    pub fn schedule_restart(&mut self, force: bool) -> RestartState {
+        let uptime = precise_time_ms() - self.started_at;
+        let _ = self.poll_exit();
+
+        self.restarts.schedule(self.exit.as_ref(), uptime, force)
+    }
+
+    pub fn has_ready_check(&self) -> bool {
+        self.ready_check.is_some()
+    }
+
+    /// Servers without a readiness probe are ready as soon as they're started.
+    pub fn readiness(&self) -> Readiness {
+        self.ready_check.as_ref().map_or(Readiness::Ready, |check| check.state())
+    }
+
+    /// Run the readiness probe if it's due, returning the new state.
+    pub fn update_readiness(&mut self) -> Readiness {
+        let mut check = match self.ready_check.take() {
+            Some(check) => check,
+            None => return Readiness::Ready,
+        };
+
+        let readiness = check.update(&*self.config.dir);
+        self.ready_check = Some(check);
+        readiness
+    }
+
+    /// Run the health check if it's due and the server is ready.
+    /// Returns `true` if the server is considered hung and should be restarted.
+    pub fn update_health(&mut self) -> bool {
+        if self.readiness() != Readiness::Ready {
+            return false;
+        }
+
+        let mut check = match self.health_check.take() {
+            Some(check) => check,
+            None => return false,
+        };
+
+        let passed = check.update(&*self.config.dir);
+
+        if passed == Some(false) {
+            println!("Health check failed for process {} ({} in a row)", self.pid(), check.failures());
+        }
+
+        let restart = check.is_hung() && check.restart_when_hung();
+        self.health_check = Some(check);
+        restart
+    }
+
+    /// Take a resource usage sample if one is due.
+    pub fn update_stats(&mut self) {
+        let pid = self.pid();
+        self.stats.update(pid);
+    }
+
+    /// The last `count` resource usage samples, oldest first.
+    pub fn stats(&self, count: usize) -> Vec<&Sample> {
+        self.stats.recent(count)
     }
 
     pub fn pid(&self) -> i32 {
         self.process.id()
     }
 
-    pub fn write_status(&mut self, w: &mut Writer) -> IoResult<()> {
+    /// The total number of automatic restarts.
+    pub fn restart_count(&self) -> u32 {
+        self.restarts.total()
+    }
+
+    pub fn info(&self, processes: &ProcessTable) -> IoResult<ServerInfo> {
+        ServerInfo::for_process(self.pid(), processes)
+    }
+
+    pub fn write_status(&mut self, w: &mut Writer, processes: &ProcessTable) -> IoResult<()> {
         if self.is_alive() {
-            writeln!(w, "Status: Running [{}]", try!(ServerInfo::for_process(self.pid())))
+            let health = match self.health_check {
+                Some(ref check) if check.is_hung() => format!(" Hung ({} failed checks)", check.failures()),
+                Some(ref check) if check.failures() > 0 => format!(" Unhealthy ({} failed checks)", check.failures()),
+                Some(_) => " Healthy".to_string(),
+                None => String::new(),
+            };
+
+            let restarts = match self.restarts.total() {
+                0 => String::new(),
+                total => format!(" Restarts: {}", total),
+            };
+
+            try!(writeln!(
+                w, "Status: Running ({}){}{} [{}]",
+                self.readiness(), health, restarts, try!(ServerInfo::for_process(self.pid(), processes))
+            ));
+
+            match self.stats.latest() {
+                Some(sample) if sample.pid == self.pid() => {
+                    try!(w.write_line("Latest sample:"));
+                    try!(w.write_line(Sample::header()));
+                    writeln!(w, "{}", sample)
+                },
+                _ => Ok(()),
+            }
         } else {
-            w.write_line("Status: Stopped")
+            let exit = match self.exits.last() {
+                Some(record) => format!("{} at {}", FormatExit(&record.exit), record.time.rfc3339()),
+                None => "unknown exit".to_string(),
+            };
+
+            match self.restarts.state() {
+                RestartState::Pending(at) => {
+                    let wait = if at > precise_time_ms() { (at - precise_time_ms()) / 1000 } else { 0 };
+                    writeln!(w, "Status: Crashed ({}), restarting in {}s", exit, wait)
+                },
+                RestartState::GaveUp => writeln!(
+                    w, "Status: Crash-looping ({}), gave up after {} restarts", exit, self.restarts.recent()
+                ),
+                _ => writeln!(w, "Status: Stopped ({})", exit),
+            }
         }                
     }
  
+    /// Stop the process. The server stays down until it's started again, even if a restart was pending.
     pub fn stop(&mut self) -> IoResult<ExitStatus> {
         if !self.is_alive() {
+            self.restarts.stopped();
             return Ok(ExitStatus::AlreadyStopped);    
         }
-        
+
+        // A failing hook shouldn't keep the server from stopping
+        if let Err(err) = run_event(Event::PreStop, &*self.name, &self.config, Some(self.pid()), None) {
+            println!("Error running pre_stop hook of \"{}\": {}", self.name, err);
+        }
+
+        let status = try!(self.stop_process());
+        self.restarts.stopped();
+
+        let exit = self.exit.clone();
+        spawn_event(Event::PostStop, &*self.name, &self.config, Some(self.pid()), exit.as_ref());
+
+        Ok(status)
+    }
+
+    fn stop_process(&mut self) -> IoResult<ExitStatus> {
         self.process.set_timeout(self.config.stop_timeout.or(STOP_TIMEOUT));
 
         if !self.config.on_stop.is_empty() {
@@ -74,20 +394,54 @@ impl Server {
                 try!(self.send_command(&*command));
             }
 
-            if let Ok(_) = self.process.wait() {
+            if let Ok(exit) = self.process.wait() {
+                self.record_exit(exit);
+                self.kill_leftovers();
                 return Ok(ExitStatus::Stopped);    
             }
         }
 
-        // Tell the server to please stop now with SIGTERM
-        try!(self.process.signal_exit());
-        if let Ok(_) = self.process.wait() {
+        // Tell the server and its children to please stop now with SIGTERM
+        try!(signal_group(self.pid(), SIGTERM));
+        if let Ok(exit) = self.process.wait() {
+            self.record_exit(exit);
+            self.kill_leftovers();
             return Ok(ExitStatus::Terminated);    
         }
 
-        // Forcibly kill the server
-        try!(self.process.signal_kill());
-        self.process.wait().map(|_| ExitStatus::Killed)                
+        // Forcibly kill the server and its children
+        try!(signal_group(self.pid(), SIGKILL));
+        let exit = try!(self.process.wait());
+        self.record_exit(exit);
+        Ok(ExitStatus::Killed)
+    }
+
+    /// Run the `on_crash` hook in the background, for a server that exited without being stopped,
+    /// unless it exited with one of its `success_exit_codes`.
+    pub fn crashed(&self) {
+        if self.restarts.is_failure(self.exit.as_ref()) {
+            spawn_event(Event::Crash, &*self.name, &self.config, Some(self.pid()), self.exit.as_ref());
+        }
+    }
+
+    /// Kill anything left in the server's process group after its own process has exited.
+    pub fn kill_leftovers(&self) {
+        if let Ok(true) = signal_group(self.pid(), SIGKILL) {
+            println!("Killed processes left behind by process {}", self.pid());
+        }
+    }
+
+    /// Reopen the log file, if the server has one.
+    pub fn reopen_log(&mut self) -> IoResult<()> {
+        match self.log_file {
+            Some(ref log_file) => log_file.lock().unwrap().reopen(),
+            None => Ok(()),
+        }
+    }
+
+    /// The server's process and all its descendants.
+    pub fn process_tree(&self, processes: &ProcessTable) -> Vec<ProcessEntry> {
+        processes.tree(self.pid())
     }
 
     pub fn send_command(&mut self, command: &str) -> IoResult<()> {
@@ -95,7 +449,7 @@ impl Server {
         stdin.write_line(command).and_then(|_| stdin.flush())
     }
 
-    pub fn read_line(&mut self, timeout_ms: u64) -> Option<String> {
+    pub fn read_line(&mut self, timeout_ms: u64) -> Option<LogLine> {
         let start = precise_time_ms();
 
         while (precise_time_ms() - start) < timeout_ms {
@@ -107,24 +461,80 @@ impl Server {
         None
     }
 
-    pub fn tail(&mut self, lines: usize) -> &[String] {
+    /// Move any lines waiting in the channel into the log buffer without blocking,
+    /// so the reader thread never stalls on a full channel.
+    pub fn pump_lines(&mut self) {
+        while let Ok(line) = self.lines.try_recv() {
+            self.push_line(line);
+        }
+
+        truncate_back(&mut self.log, MAX_LINES);
+    }
+
+    fn push_line(&mut self, line: LogLine) {
+        self.log.push(line);
+        self.line_count += 1;
+    }
+
+    /// The number of lines received since the process started.
+    pub fn line_count(&self) -> u64 {
+        self.line_count
+    }
+
+    /// Get the buffered lines received after the first `count` lines.
+    pub fn lines_since(&self, count: u64) -> &[LogLine] {
+        let first = self.line_count - self.log.len() as u64;
+        let offset = if count > first { (count - first) as usize } else { 0 };
+
+        &self.log[min(offset, self.log.len())..]
+    }
+
+    /// Get the last `lines` lines of output, optionally only from one stream.
+    pub fn tail(&mut self, lines: usize, stream: Option<Stream>) -> Vec<&LogLine> {
         while let Some(line) = self.read_line(100) {
-            self.log.push(line);     
+            self.push_line(line);     
         }
         
-        truncate_back(&mut self.log, 80);
-        
-        let offset = if lines > self.log.len() {
-            0    
-        } else {
-            self.log.len() - lines    
-        };
+        truncate_back(&mut self.log, MAX_LINES);
 
-        &self.log[offset..]
+        let mut tail: Vec<_> = self.log.iter().rev()
+            .filter(|line| stream.map_or(true, |stream| line.stream == stream))
+            .take(lines)
+            .collect();
+
+        tail.reverse();
+        tail
     }
-    
-    pub fn auto_restart(&self) -> bool {
-        self.config.auto_restart.unwrap_or(false) 
+}
+
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl fmt::String for Stream {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.pad(match *self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct LogLine {
+    pub stream: Stream,
+    pub text: String,
+}
+
+impl fmt::String for LogLine {
+    /// Stderr lines are marked, stdout lines are shown as-is.
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.stream {
+            Stream::Stdout => fmt.write_str(&*self.text),
+            Stream::Stderr => fmt.write_fmt(format_args!("[stderr] {}", self.text)),
+        }
     }
 }
 
@@ -151,51 +561,73 @@ pub struct ServerInfo {
     pub percent_cpu: f32,
     pub memory_usage: usize,
     pub uptime: u64, 
+    /// Totals for the process and all its descendants
+    pub tree_processes: usize,
+    pub tree_percent_cpu: f32,
+    pub tree_memory_usage: u64,
 }
 
 impl ServerInfo {
-    fn for_process(pid: i32) -> IoResult<ServerInfo> {
-        use libc::sysconf;
-
-        fn ticks_per_second() -> u64 {
-            use libc::consts::os::sysconf::_SC_CLK_TCK;
-
-            unsafe { sysconf(_SC_CLK_TCK) as u64 }
-        }
-
+    fn for_process(pid: i32, processes: &ProcessTable) -> IoResult<ServerInfo> {
         fn ticks_to_s(ticks: u64) -> u64 {
             ticks / ticks_per_second()    
         }
 
         let ref proc_uptime_path = Path::new("/proc/uptime");
         let proc_uptime = try!(File::open(proc_uptime_path).read_to_string());
-        let uptime = proc_uptime.words().next().and_then(|s| s.parse::<f64>()).unwrap() as u64;
+        let uptime = try!(
+            proc_uptime.words().next().and_then(|s| s.parse::<f64>()).ok_or(unexpected_proc(proc_uptime_path))
+        ) as u64;
 
         let ref proc_stat_path = Path::new(format!("/proc/{}/stat", pid));
         let proc_stat = try!(File::open(proc_stat_path).read_to_string());
 
-        // Indexes into `columns` taken from the man page for /proc/[pid]/stat
-        // Mind that the man page starts the indexes from 1 but these start from 0
-        let columns: Vec<_> = proc_stat.words().map(ToOwned::to_owned).collect();
+        // The command name in parentheses can contain spaces, so count columns from after it
+        let columns: Vec<&str> = proc_stat[proc_stat.rfind(')').unwrap_or(0) + 1..].words().collect();
+
+        // Indexes are those from the man page for /proc/[pid]/stat, minus 3
+        let column = |index: usize| {
+            columns.get(index - 3).and_then(|col| col.parse::<u64>()).ok_or(unexpected_proc(proc_stat_path))
+        };
 
         // Get kernel time and user time
-        let cputime_ticks: u64 = columns[13].parse::<u64>().unwrap() + columns[14].parse().unwrap(); 
-        let start_time = ticks_to_s(columns[21].parse().unwrap());
+        let cputime_ticks = try!(column(14)) + try!(column(15));
+        let start_time = ticks_to_s(try!(column(22)));
         
-        let runtime = uptime - start_time;
+        // Saturating, the two clocks are read at different times
+        let runtime = uptime.saturating_sub(start_time);
+
+        let percent_cpu = |ticks: u64| if runtime > 0 {
+            (ticks as f64 * 100.0 / ticks_per_second() as f64 / runtime as f64) as f32
+        } else {
+            0.0
+        };
+
+        let tree = processes.tree(pid);
          
         Ok(ServerInfo {
             pid: pid,
-            percent_cpu: ticks_to_s(cputime_ticks * 100) as f32 / runtime as f32,
-            memory_usage: columns[23].parse::<usize>().unwrap() * os::page_size(),
+            percent_cpu: percent_cpu(cputime_ticks),
+            memory_usage: try!(column(24)) as usize * os::page_size(),
             uptime: runtime,
+            tree_processes: tree.len(),
+            tree_percent_cpu: percent_cpu(tree.iter().map(|process| process.cpu_ticks).sum()),
+            tree_memory_usage: tree.iter().map(|process| process.rss).sum(),
         })             
     }
 }
 
+fn unexpected_proc(path: &Path) -> IoError {
+    IoError {
+        kind: IoErrorKind::OtherIoError,
+        desc: "Couldn't parse a /proc file",
+        detail: Some(path.display().to_string()),
+    }
+}
+
 impl fmt::String for ServerInfo {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        fmt.write_fmt(
+        try!(fmt.write_fmt(
             format_args!(
                 "PID: {} Avg CPU: {:.02}% Memory: {} Uptime: {}",
                 self.pid,
@@ -203,27 +635,63 @@ impl fmt::String for ServerInfo {
                 FormatBytes(self.memory_usage as u64),
                 FormatTime::from_s(self.uptime)
             )
+        ));
+
+        if self.tree_processes <= 1 {
+            return Ok(());
+        }
+
+        fmt.write_fmt(
+            format_args!(
+                " Tree: {} processes, Avg CPU: {:.02}% Memory: {}",
+                self.tree_processes,
+                self.tree_percent_cpu,
+                FormatBytes(self.tree_memory_usage)
+            )
         )
     } 
 }
 
 const MAX_LINES: usize = 80;
 
-fn read_lines_threaded(stream: PipeStream) -> Receiver<String> {
-    let (tx, rx) = sync_channel(MAX_LINES);
+fn log_watches(ready_check: &Option<ReadyCheck>, health_check: &Option<HealthCheck>) -> Vec<LogWatch> {
+    ready_check.as_ref().and_then(|check| check.watch()).into_iter()
+        .chain(health_check.as_ref().and_then(|check| check.watch()).into_iter())
+        .collect()
+}
 
+fn read_lines_threaded(
+    pipe: PipeStream, stream: Stream, tx: SyncSender<LogLine>, log_file: Option<Arc<Mutex<LogFile>>>,
+    watches: Arc<Mutex<Vec<LogWatch>>>
+) {
     Thread::spawn(move || {
-        let mut reader = BufferedReader::new(stream);
+        let mut reader = BufferedReader::new(pipe);
         for line in reader.lines() {
-            let line = line.unwrap();
+            let text = match line {
+                Ok(line) => line.trim_right_matches('\n').to_owned(),
+                Err(_) => break,
+            };
+
+            if text.trim().is_empty() { continue; }
+
+            for watch in watches.lock().unwrap().iter() {
+                watch.check(&*text);
+            }
+
+            if let Some(ref log_file) = log_file {
+                let res = log_file.lock().unwrap().write_line(&*format!("[{}] {}", stream, text));
 
-            if !line.trim().is_empty() {
-                tx.send(line).unwrap();
-            }           
+                if let Err(err) = res {
+                    println!("Error writing to log file: {}", err);
+                }
+            }
+
+            if tx.send(LogLine { stream: stream, text: text }).is_err() {
+                // The `Server` was dropped, but keep draining so the process doesn't block
+                continue;
+            }
         }
     });
-
-    rx
 }
 
 fn truncate_back<T>(vec: &mut Vec<T>, len: usize) {
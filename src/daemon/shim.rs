@@ -0,0 +1,248 @@
+//! Changing a server's process before it runs the server's command.
+//!
+//! `Command` can't run code in the child between fork and exec, so instead the daemon runs its own
+//! binary as `shepherd exec-shim <options> -- <command> [args...]`. The shim applies the options
+//! and then execs the command in place, keeping the PID.
+//!
+//! The shim reports failures on fd 3, which is closed on exec: if the daemon reads EOF, the command
+//! is running; anything else is an error message.
+
+use config::ServerConfig;
+use user::Credentials;
+use super::cgroup::Cgroup;
+
+use libc::{c_char, c_int, c_ulong, gid_t, mode_t, uid_t};
+
+use rustc_serialize::json;
+
+use std::ffi::CString;
+use std::io::{File, IoError, IoErrorKind, IoResult};
+use std::io::pipe::PipeStream;
+use std::io::process::{Command, Process, StdioContainer};
+use std::os;
+use std::ptr;
+
+/// The hidden argument that makes `shepherd` act as the shim.
+pub const SHIM_OP: &'static str = "exec-shim";
+
+const STATUS_FD: c_int = 3;
+const F_SETFD: c_int = 2;
+const FD_CLOEXEC: c_int = 1;
+
+// Linux numbering
+const RLIMIT_CORE: c_int = 4;
+const RLIMIT_NOFILE: c_int = 7;
+const RLIMIT_AS: c_int = 9;
+
+#[repr(C)]
+struct rlimit {
+    rlim_cur: c_ulong,
+    rlim_max: c_ulong,
+}
+
+extern {
+    fn setgroups(size: c_int, list: *const gid_t) -> c_int;
+    fn setgid(gid: gid_t) -> c_int;
+    fn setuid(uid: uid_t) -> c_int;
+    fn umask(mask: mode_t) -> mode_t;
+    fn execvp(file: *const c_char, argv: *const *const c_char) -> c_int;
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    fn setrlimit(resource: c_int, rlim: *const rlimit) -> c_int;
+}
+
+#[derive(Clone, Show, RustcEncodable, RustcDecodable)]
+pub struct ShimOptions {
+    pub credentials: Credentials,
+    pub rlimits: Vec<Rlimit>,
+    /// The `cgroup.procs` file to join
+    pub cgroup: Option<String>,
+}
+
+#[derive(Clone, Show, RustcEncodable, RustcDecodable)]
+pub struct Rlimit {
+    pub name: String,
+    pub resource: i32,
+    /// Used for both the soft and hard limit
+    pub value: u64,
+}
+
+impl ShimOptions {
+    /// The options a server needs, or `None` if it can be run directly.
+    pub fn for_config(config: &ServerConfig, cgroup: Option<&Cgroup>) -> IoResult<Option<ShimOptions>> {
+        let credentials = try!(credentials(config));
+
+        let mut rlimits = Vec::new();
+
+        if let Some(ref limits) = config.limits {
+            let configured = [
+                ("open_files", RLIMIT_NOFILE, limits.open_files),
+                ("core_size", RLIMIT_CORE, limits.core_size),
+                ("address_space", RLIMIT_AS, limits.address_space),
+            ];
+
+            for &(name, resource, value) in configured.iter() {
+                if let Some(value) = value {
+                    rlimits.push(Rlimit { name: name.to_string(), resource: resource as i32, value: value });
+                }
+            }
+        }
+
+        if credentials.is_inherited() && rlimits.is_empty() && cgroup.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(ShimOptions {
+            credentials: credentials,
+            rlimits: rlimits,
+            cgroup: cgroup.map(|cgroup| cgroup.procs_path()),
+        }))
+    }
+
+    /// The options for a server's hooks, which run as the server's user but aren't limited like it.
+    /// `None` if they can be run directly.
+    pub fn for_hooks(config: &ServerConfig) -> IoResult<Option<ShimOptions>> {
+        let credentials = try!(credentials(config));
+
+        if credentials.is_inherited() {
+            return Ok(None);
+        }
+
+        Ok(Some(ShimOptions {
+            credentials: credentials,
+            rlimits: Vec::new(),
+            cgroup: None,
+        }))
+    }
+
+    /// A command that runs `program` with `args` through the shim.
+    pub fn command(&self, program: &str, args: &[String]) -> IoResult<Command> {
+        let shepherd = try!(os::self_exe_name().ok_or(IoError {
+            kind: IoErrorKind::OtherIoError,
+            desc: "Couldn't find the shepherd executable to run the server through",
+            detail: None,
+        }));
+
+        let mut command = Command::new(shepherd);
+        command.arg(SHIM_OP).arg(json::encode(self)).arg("--").arg(program).args(args)
+            .extra_io(StdioContainer::CreatePipe(false, true));
+
+        Ok(command)
+    }
+}
+
+fn credentials(config: &ServerConfig) -> IoResult<Credentials> {
+    Credentials::for_config(config).map_err(|msg| IoError {
+        kind: IoErrorKind::InvalidInput,
+        desc: "Invalid user or group",
+        detail: Some(msg),
+    })
+}
+
+/// Wait for a process started through the shim to exec the server's command, returning the shim's error if it didn't.
+pub fn check_exec(process: &mut Process) -> IoResult<()> {
+    let mut status = match process.extra_io.get_mut(0).and_then(|pipe| pipe.take()) {
+        Some(status) => status,
+        None => return Ok(()),
+    };
+
+    let msg = try!(status.read_to_string());
+
+    if msg.is_empty() {
+        return Ok(());
+    }
+
+    let _ = process.wait();
+
+    Err(IoError {
+        kind: IoErrorKind::OtherIoError,
+        desc: "Couldn't start the server",
+        detail: Some(msg),
+    })
+}
+
+/// Run as the shim. `args` are the ones after `exec-shim`. Only returns if something went wrong.
+pub fn run(args: &[String]) {
+    unsafe { fcntl(STATUS_FD, F_SETFD, FD_CLOEXEC); }
+
+    if let Err(msg) = exec(args) {
+        if let Ok(mut status) = PipeStream::open(STATUS_FD) {
+            let _ = status.write_str(&*msg);
+        }
+
+        os::set_exit_status(127);
+    }
+}
+
+fn exec(args: &[String]) -> Result<(), String> {
+    if args.len() < 3 || &*args[1] != "--" {
+        return Err(format!("Usage: shepherd {} <options> -- <command> [args...]", SHIM_OP));
+    }
+
+    let options: ShimOptions = try!(json::decode(&*args[0]).map_err(|err| format!("Invalid shim options: {}", err)));
+    // Join the cgroup and lower limits while we may still have the privileges to
+    try!(join_cgroup(options.cgroup.as_ref().map(|path| &**path)));
+    try!(apply_rlimits(&*options.rlimits));
+    try!(apply_credentials(&options.credentials));
+
+    let command: Vec<CString> = args[2..].iter().map(|arg| CString::from_slice(arg.as_bytes())).collect();
+    let mut argv: Vec<*const c_char> = command.iter().map(|arg| arg.as_ptr()).collect();
+    argv.push(ptr::null());
+
+    unsafe { execvp(argv[0], argv.as_ptr()); }
+
+    Err(format!("Couldn't run \"{}\": {}", args[2], IoError::last_error()))
+}
+
+fn join_cgroup(procs: Option<&str>) -> Result<(), String> {
+    let procs = match procs {
+        Some(procs) => procs,
+        None => return Ok(()),
+    };
+
+    let pid = unsafe { ::libc::getpid() };
+
+    File::create(&Path::new(procs)).and_then(|mut file| file.write_str(&*pid.to_string()))
+        .map_err(|err| format!("Couldn't join cgroup {}: {}", procs, err))
+}
+
+fn apply_rlimits(rlimits: &[Rlimit]) -> Result<(), String> {
+    for limit in rlimits.iter() {
+        let rlim = rlimit {
+            rlim_cur: limit.value as c_ulong,
+            rlim_max: limit.value as c_ulong,
+        };
+
+        if unsafe { setrlimit(limit.resource as c_int, &rlim) } != 0 {
+            return Err(format!("Couldn't set {} limit to {}: {}", limit.name, limit.value, IoError::last_error()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Groups have to be changed first, while we still have the privileges to.
+fn apply_credentials(credentials: &Credentials) -> Result<(), String> {
+    if let Some(ref groups) = credentials.groups {
+        if unsafe { setgroups(groups.len() as c_int, groups.as_ptr()) } != 0 {
+            return Err(format!("Couldn't set supplementary groups to {:?}: {}", groups, IoError::last_error()));
+        }
+    }
+
+    if let Some(gid) = credentials.gid {
+        if unsafe { setgid(gid) } != 0 {
+            return Err(format!("Couldn't switch to group {}: {}", gid, IoError::last_error()));
+        }
+    }
+
+    if let Some(uid) = credentials.uid {
+        if unsafe { setuid(uid) } != 0 {
+            return Err(format!("Couldn't switch to user {}: {}", uid, IoError::last_error()));
+        }
+    }
+
+    if let Some(mask) = credentials.umask {
+        unsafe { umask(mask as mode_t); }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Signals sent to the daemon, delivered through a self-pipe that the main loop checks each tick.
+//!
+//! SIGTERM and SIGINT stop every server the way `kill-daemon` does and exit, SIGHUP reloads the config
+//! like `reload-config`, and SIGUSR1 reopens the servers' log files, e.g. after logrotate moved them.
+
+use super::Daemon;
+use super::tree::SIGTERM;
+
+use libc::{c_int, c_void, size_t, ssize_t};
+
+use std::io::{IoError, IoResult, stdio};
+
+const SIGHUP: c_int = 1;
+const SIGINT: c_int = 2;
+const SIGUSR1: c_int = 10;
+
+const F_SETFD: c_int = 2;
+const F_SETFL: c_int = 4;
+const FD_CLOEXEC: c_int = 1;
+const O_NONBLOCK: c_int = 0o4000;
+
+type SigHandler = extern fn(c_int);
+
+extern {
+    fn pipe(fds: *mut c_int) -> c_int;
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    fn read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t;
+    fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t;
+    fn signal(signum: c_int, handler: SigHandler) -> size_t;
+}
+
+/// The write end of the pipe, for the handler
+static mut SIGNAL_FD: c_int = -1;
+
+/// Only does what's safe in a signal handler: writes the signal number to the pipe.
+extern fn on_signal(signum: c_int) {
+    let byte = signum as u8;
+    unsafe { write(SIGNAL_FD, &byte as *const u8 as *const c_void, 1); }
+}
+
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum Signal {
+    /// SIGTERM or SIGINT
+    Terminate(&'static str),
+    /// SIGHUP
+    Reload,
+    /// SIGUSR1
+    ReopenLogs,
+}
+
+pub struct Signals {
+    read_fd: c_int,
+}
+
+impl Signals {
+    /// Create the pipe and install the handlers. Must only be called once.
+    pub fn install() -> IoResult<Signals> {
+        let mut fds = [0 as c_int; 2];
+
+        if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(IoError::last_error());
+        }
+
+        unsafe {
+            for &fd in fds.iter() {
+                // A full pipe already has signals waiting, so the handler must never block
+                fcntl(fd, F_SETFL, O_NONBLOCK);
+                fcntl(fd, F_SETFD, FD_CLOEXEC);
+            }
+
+            SIGNAL_FD = fds[1];
+
+            for &signum in [SIGHUP, SIGINT, SIGUSR1, SIGTERM].iter() {
+                signal(signum, on_signal);
+            }
+        }
+
+        Ok(Signals { read_fd: fds[0] })
+    }
+
+    /// The signals received since the last call, in order.
+    pub fn pending(&mut self) -> Vec<Signal> {
+        let mut buf = [0u8; 64];
+        let mut signals = Vec::new();
+
+        loop {
+            let count = unsafe { read(self.read_fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t) };
+
+            if count <= 0 { break; }
+
+            for &signum in buf[..count as usize].iter() {
+                signals.push(match signum as c_int {
+                    SIGHUP => Signal::Reload,
+                    SIGINT => Signal::Terminate("SIGINT"),
+                    SIGUSR1 => Signal::ReopenLogs,
+                    _ => Signal::Terminate("SIGTERM"),
+                });
+            }
+        }
+
+        signals
+    }
+}
+
+impl Daemon {
+    /// Act on the signals received since the last tick. Returns `false` if the daemon should exit.
+    pub fn handle_signals(&mut self, signals: &mut Signals) -> bool {
+        for received in signals.pending().into_iter() {
+            match received {
+                Signal::Terminate(name) => {
+                    println!("Received {}, stopping servers...", name);
+
+                    match self.stop_everything(&mut stdio::stdout()) {
+                        Ok(ref failed) if failed.is_empty() => (),
+                        Ok(failed) => println!("Failed to stop {:?}, they will die now.", failed),
+                        Err(err) => println!("Error stopping servers: {}", err),
+                    }
+
+                    return false;
+                },
+                Signal::Reload => {
+                    println!("Received SIGHUP, reloading config...");
+                    self.notify("RELOADING=1");
+                    self.reload_on_signal();
+                    self.notify("READY=1");
+                },
+                Signal::ReopenLogs => {
+                    println!("Received SIGUSR1, reopening log files...");
+
+                    for (server, instance) in self.servers.iter_mut() {
+                        if let Err(err) = instance.reopen_log() {
+                            println!("Error reopening the log file of \"{}\": {}", server, err);
+                        }
+                    }
+                },
+            }
+        }
+
+        true
+    }
+}
@@ -0,0 +1,182 @@
+//! Periodic samples of a server's resource usage, read from `/proc`.
+
+use util::{FormatBytes, precise_time_ms, ticks_per_second};
+
+use time::{self, Tm};
+
+use std::collections::RingBuf;
+use std::fmt;
+use std::io::{File, IoResult};
+use std::io::fs;
+use std::os;
+
+/// Default time between samples, in seconds
+pub const DEFAULT_INTERVAL: u64 = 5;
+/// Default number of samples kept per server
+pub const DEFAULT_HISTORY: usize = 120;
+
+#[derive(Clone)]
+pub struct Sample {
+    pub pid: i32,
+    pub time: Tm,
+    /// Since the previous sample of the same process; `None` for its first sample
+    pub cpu_percent: Option<f64>,
+    pub rss: u64,
+    /// From `smaps_rollup`, which needs Linux 4.14 and permission to read it
+    pub pss: Option<u64>,
+    pub uss: Option<u64>,
+    pub threads: u64,
+    pub fds: Option<u64>,
+    /// Total bytes read and written from storage, from `/proc/<pid>/io`
+    pub read_bytes: Option<u64>,
+    pub write_bytes: Option<u64>,
+}
+
+impl Sample {
+    pub fn header() -> &'static str {
+        "TIME                      PID      CPU%       RSS       PSS       USS  THREADS    FDS      READ     WRITE"
+    }
+}
+
+impl fmt::String for Sample {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fn opt_bytes(bytes: Option<u64>) -> String {
+            bytes.map_or("-".to_string(), |bytes| FormatBytes(bytes).to_string())
+        }
+
+        fmt.write_fmt(
+            format_args!(
+                "{:<25} {:<6} {:>6} {:>9} {:>9} {:>9} {:>8} {:>6} {:>9} {:>9}",
+                self.time.rfc3339().to_string(),
+                self.pid,
+                self.cpu_percent.map_or("-".to_string(), |cpu| format!("{:.1}", cpu)),
+                FormatBytes(self.rss).to_string(),
+                opt_bytes(self.pss),
+                opt_bytes(self.uss),
+                self.threads,
+                self.fds.map_or("-".to_string(), |fds| fds.to_string()),
+                opt_bytes(self.read_bytes),
+                opt_bytes(self.write_bytes)
+            )
+        )
+    }
+}
+
+/// Takes samples at a fixed interval and keeps the last few, oldest first.
+/// Carried across restarts of the same server.
+pub struct Sampler {
+    /// In ms
+    interval: u64,
+    capacity: usize,
+    last_sample: Option<u64>,
+    /// PID, CPU ticks and time of the last sample, for working out CPU usage in between
+    last_cpu: Option<(i32, u64, u64)>,
+    samples: RingBuf<Sample>,
+}
+
+impl Sampler {
+    pub fn new(interval: u64, capacity: usize) -> Sampler {
+        Sampler {
+            interval: interval * 1000,
+            capacity: capacity,
+            last_sample: None,
+            last_cpu: None,
+            samples: RingBuf::with_capacity(capacity),
+        }
+    }
+
+    /// Take a sample of `pid` if one is due.
+    pub fn update(&mut self, pid: i32) {
+        let now = precise_time_ms();
+
+        if self.last_sample.map_or(false, |last| now - last < self.interval) {
+            return;
+        }
+
+        self.last_sample = Some(now);
+
+        // The process may have exited since it was polled
+        if let Ok(sample) = self.sample(pid, now) {
+            if self.samples.len() == self.capacity {
+                self.samples.pop_front();
+            }
+
+            self.samples.push_back(sample);
+        }
+    }
+
+    fn sample(&mut self, pid: i32, now: u64) -> IoResult<Sample> {
+        let stat = try!(read_proc(pid, "stat"));
+        // The command name in parentheses can contain spaces, so count columns from after it
+        let columns: Vec<&str> = stat[stat.rfind(')').unwrap_or(0) + 1..].words().collect();
+
+        // Indexes are those from the man page for /proc/[pid]/stat, minus 3
+        let column = |index: usize| columns.get(index - 3).and_then(|col| col.parse::<u64>()).unwrap_or(0);
+
+        let cpu_ticks = column(14) + column(15);
+        let threads = column(20);
+        let rss = column(24) * os::page_size() as u64;
+
+        let cpu_percent = match self.last_cpu {
+            Some((last_pid, last_ticks, last_time)) if last_pid == pid && now > last_time => {
+                let cpu_s = (cpu_ticks - last_ticks) as f64 / ticks_per_second() as f64;
+                Some(cpu_s * 100_000.0 / (now - last_time) as f64)
+            },
+            _ => None,
+        };
+
+        self.last_cpu = Some((pid, cpu_ticks, now));
+
+        let (pss, uss) = match read_proc(pid, "smaps_rollup") {
+            Ok(smaps) => {
+                let private = field_kb(&*smaps, "Private_Clean:")
+                    .and_then(|clean| field_kb(&*smaps, "Private_Dirty:").map(|dirty| clean + dirty));
+                (field_kb(&*smaps, "Pss:"), private)
+            },
+            Err(_) => (None, None),
+        };
+
+        let io = read_proc(pid, "io").ok();
+        let io_field = |name: &str| io.as_ref().and_then(|io| field(&**io, name));
+
+        Ok(Sample {
+            pid: pid,
+            time: time::now(),
+            cpu_percent: cpu_percent,
+            rss: rss,
+            pss: pss,
+            uss: uss,
+            threads: threads,
+            fds: fs::readdir(&Path::new(format!("/proc/{}/fd", pid))).ok().map(|fds| fds.len() as u64),
+            read_bytes: io_field("read_bytes:"),
+            write_bytes: io_field("write_bytes:"),
+        })
+    }
+
+    pub fn latest(&self) -> Option<&Sample> {
+        self.samples.back()
+    }
+
+    /// The last `count` samples, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<&Sample> {
+        let skip = if self.samples.len() > count { self.samples.len() - count } else { 0 };
+        self.samples.iter().skip(skip).collect()
+    }
+}
+
+fn read_proc(pid: i32, file: &str) -> IoResult<String> {
+    File::open(&Path::new(format!("/proc/{}/{}", pid, file))).read_to_string()
+}
+
+/// Find a line like `name: 1234` and parse the number.
+fn field(contents: &str, name: &str) -> Option<u64> {
+    contents.lines()
+        .filter(|line| line.starts_with(name))
+        .filter_map(|line| line[name.len()..].words().next().and_then(|value| value.parse()))
+        .next()
+}
+
+/// Like `field()` for lines like `name: 1234 kB`, returning bytes.
+fn field_kb(contents: &str, name: &str) -> Option<u64> {
+    field(contents, name).map(|kb| kb * 1024)
+}
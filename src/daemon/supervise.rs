@@ -0,0 +1,403 @@
+//! Running under a supervisor with `shepherd run --foreground`, e.g. as a systemd `Type=notify` service or
+//! as PID 1 in a container.
+//!
+//! The daemon stays in the foreground and writes its output to stdout as one JSON object per line, with
+//! the time and the stream each line was written to. If `NOTIFY_SOCKET` is set, the daemon tells systemd
+//! when it's ready, how many instances are running, and when it's reloading or stopping.
+//!
+//! Orphans are reparented to the daemon, as PID 1 or otherwise as a child subreaper, and reaped. Processes the
+//! daemon spawns itself are tracked with `track_child()` so their exit statuses are left for whoever waits on them.
+
+use super::{Daemon, claim, run};
+use config::Config;
+use util::precise_time_ms;
+
+use libc::{c_char, c_int, c_ulong, c_void, pid_t, size_t, ssize_t};
+
+use rustc_serialize::json;
+
+use std::collections::HashSet;
+use std::io::{BufferedReader, IoError, IoErrorKind, IoResult};
+use std::io::pipe::PipeStream;
+use std::io::timer::sleep;
+use std::mem;
+use std::os;
+use std::sync::{StaticMutex, MUTEX_INIT};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread::Thread;
+use std::time::Duration;
+
+use time;
+
+const AF_UNIX: c_int = 1;
+const SOCK_DGRAM: c_int = 2;
+const SOCK_CLOEXEC: c_int = 0o2000000;
+
+const F_SETFD: c_int = 2;
+const FD_CLOEXEC: c_int = 1;
+
+const WNOHANG: c_int = 1;
+
+const PR_SET_CHILD_SUBREAPER: c_int = 36;
+
+/// How often to look for zombies, in ms
+const REAP_INTERVAL: u64 = 1000;
+/// How long to wait at exit for structured output to be written, in ms.
+/// Hooks still running in the background can keep the pipes open.
+const FLUSH_TIMEOUT: u64 = 5000;
+
+#[repr(C)]
+struct sockaddr_un {
+    sun_family: u16,
+    sun_path: [c_char; 108],
+}
+
+extern {
+    fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+    fn sendto(fd: c_int, buf: *const c_void, len: size_t, flags: c_int, addr: *const sockaddr_un, addr_len: u32)
+        -> ssize_t;
+    fn pipe(fds: *mut c_int) -> c_int;
+    fn dup(fd: c_int) -> c_int;
+    fn dup2(old: c_int, new: c_int) -> c_int;
+    fn close(fd: c_int) -> c_int;
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    fn waitpid(pid: pid_t, status: *mut c_int, options: c_int) -> pid_t;
+    fn prctl(option: c_int, ...) -> c_int;
+}
+
+/// Guards `TRACKED`
+static TRACKED_LOCK: StaticMutex = MUTEX_INIT;
+/// The PIDs of `TrackedChild`ren, allocated on first use
+static mut TRACKED: *mut HashSet<i32> = 0 as *mut HashSet<i32>;
+
+/// A child the daemon spawned and will wait on itself, from any thread. It isn't reaped as an orphan while this
+/// is alive, so keep it until the child has been waited on.
+pub struct TrackedChild {
+    pid: i32,
+}
+
+impl Drop for TrackedChild {
+    fn drop(&mut self) {
+        let _lock = TRACKED_LOCK.lock();
+        unsafe { (*TRACKED).remove(&self.pid); }
+    }
+}
+
+/// Leave the child `pid` to its spawner until the result is dropped.
+pub fn track_child(pid: i32) -> TrackedChild {
+    let _lock = TRACKED_LOCK.lock();
+
+    unsafe {
+        if TRACKED.is_null() {
+            TRACKED = mem::transmute(Box::new(HashSet::<i32>::new()));
+        }
+
+        (*TRACKED).insert(pid);
+    }
+
+    TrackedChild { pid: pid }
+}
+
+fn is_tracked(pid: i32) -> bool {
+    let _lock = TRACKED_LOCK.lock();
+    unsafe { !TRACKED.is_null() && (*TRACKED).contains(&pid) }
+}
+
+/// Run the daemon in the foreground until it's stopped.
+pub fn run_foreground(config: Config) {
+    let pid_file = match claim(&config).and_then(|pid_file| pid_file.write_pid().map(|_| pid_file)) {
+        Ok(pid_file) => pid_file,
+        Err(err) => {
+            println!("Not starting: {}", err);
+            os::set_exit_status(1);
+            return;
+        },
+    };
+
+    // PID 1 gets orphans anyway
+    if unsafe { ::libc::getpid() } != 1 && unsafe { prctl(PR_SET_CHILD_SUBREAPER, 1 as c_ulong) } != 0 {
+        println!("Couldn't become a child subreaper, orphans won't be reaped: {}", IoError::last_error());
+    }
+
+    let output = match log_structured() {
+        Ok(output) => Some(output),
+        Err(err) => {
+            println!("Couldn't redirect output, it won't be structured: {}", err);
+            None
+        },
+    };
+
+    let notifier = match Notifier::from_env() {
+        Ok(notifier) => notifier,
+        Err(err) => {
+            println!("Couldn't connect to NOTIFY_SOCKET, systemd won't be notified: {}", err);
+            None
+        },
+    };
+
+    run(config, Some(Supervisor {
+        notifier: notifier,
+        zombies: Vec::new(),
+        last_reap: 0,
+        status: String::new(),
+    }), pid_file);
+
+    if let Some(output) = output {
+        output.finish();
+    }
+}
+
+/// What the daemon does differently in the foreground
+pub struct Supervisor {
+    notifier: Option<Notifier>,
+    /// Zombies seen by the last check
+    zombies: Vec<i32>,
+    last_reap: u64,
+    /// The last `STATUS` sent
+    status: String,
+}
+
+/// Sends sd_notify messages to the socket systemd names in `NOTIFY_SOCKET`.
+struct Notifier {
+    fd: c_int,
+    addr: sockaddr_un,
+    addr_len: u32,
+}
+
+impl Notifier {
+    /// `None` if `NOTIFY_SOCKET` isn't set. It's removed from the environment, servers shouldn't see it.
+    fn from_env() -> IoResult<Option<Notifier>> {
+        let path = match os::getenv("NOTIFY_SOCKET") {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        os::unsetenv("NOTIFY_SOCKET");
+
+        let mut addr = sockaddr_un {
+            sun_family: AF_UNIX as u16,
+            sun_path: [0; 108],
+        };
+
+        // Leave room for the NUL
+        if path.is_empty() || path.len() >= addr.sun_path.len() {
+            return Err(IoError {
+                kind: IoErrorKind::InvalidInput,
+                desc: "Invalid NOTIFY_SOCKET",
+                detail: Some(format!("{:?}", path)),
+            });
+        }
+
+        for (dest, &byte) in addr.sun_path.iter_mut().zip(path.as_bytes().iter()) {
+            *dest = byte as c_char;
+        }
+
+        // A leading `@` is an abstract socket, whose name starts with a NUL and isn't NUL terminated
+        let addr_len = if path.starts_with("@") {
+            addr.sun_path[0] = 0;
+            mem::size_of::<u16>() + path.len()
+        } else {
+            mem::size_of::<u16>() + path.len() + 1
+        };
+
+        let fd = unsafe { socket(AF_UNIX, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+
+        Ok(Some(Notifier {
+            fd: fd,
+            addr: addr,
+            addr_len: addr_len as u32,
+        }))
+    }
+
+    /// Send newline separated `VARIABLE=value` assignments.
+    fn notify(&self, state: &str) -> IoResult<()> {
+        let sent = unsafe {
+            sendto(self.fd, state.as_ptr() as *const c_void, state.len() as size_t, 0, &self.addr, self.addr_len)
+        };
+
+        if sent < 0 { Err(IoError::last_error()) } else { Ok(()) }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        unsafe { close(self.fd); }
+    }
+}
+
+impl Daemon {
+    /// Send `state` to systemd, if it's listening.
+    pub fn notify(&self, state: &str) {
+        let notifier = match self.supervisor {
+            Some(Supervisor { notifier: Some(ref notifier), .. }) => notifier,
+            _ => return,
+        };
+
+        if let Err(err) = notifier.notify(state) {
+            println!("Error notifying systemd of {:?}: {}", state, err);
+        }
+    }
+
+    /// Called every tick in the foreground: reap zombies and keep systemd's status current.
+    pub fn supervise(&mut self) {
+        if self.supervisor.is_none() {
+            return;
+        }
+
+        self.reap_zombies();
+
+        let mut names: Vec<String> = self.servers.keys().cloned().collect();
+        names.sort();
+        let total = names.len();
+        let running = names.iter().filter(|name| self.is_running(&***name)).count();
+
+        let status = format!("{} of {} instances running", running, total);
+
+        if self.supervisor.as_ref().map_or(false, |supervisor| supervisor.status == status) {
+            return;
+        }
+
+        self.notify(&*format!("STATUS={}", status));
+        self.supervisor.as_mut().unwrap().status = status;
+    }
+
+    /// Reap children the daemon didn't start itself.
+    ///
+    /// Processes started through `Command` are reaped by whoever waits on them, so a zombie is only reaped
+    /// here if it isn't a server's process or tracked, and is still around the next time we look.
+    fn reap_zombies(&mut self) {
+        let now = precise_time_ms();
+
+        if self.supervisor.as_ref().map_or(true, |supervisor| now - supervisor.last_reap < REAP_INTERVAL) {
+            return;
+        }
+
+        let servers: Vec<i32> = self.servers.values().map(|instance| instance.pid()).collect();
+
+        let zombies: Vec<i32> = match self.process_table() {
+            Ok(processes) => processes.children_of(unsafe { ::libc::getpid() }).into_iter()
+                .filter(|child| &*child.state == "Z" && !servers.contains(&child.pid) && !is_tracked(child.pid))
+                .map(|child| child.pid)
+                .collect(),
+            Err(err) => return println!("Error looking for zombies: {}", err),
+        };
+
+        let supervisor = self.supervisor.as_mut().unwrap();
+
+        for &pid in zombies.iter().filter(|pid| supervisor.zombies.contains(*pid)) {
+            let mut status = 0;
+
+            if unsafe { waitpid(pid, &mut status, WNOHANG) } == pid {
+                println!("Reaped orphaned process {}", pid);
+            }
+        }
+
+        supervisor.zombies = zombies;
+        supervisor.last_reap = now;
+    }
+}
+
+#[derive(RustcEncodable)]
+struct LogEntry<'a> {
+    time: String,
+    stream: &'static str,
+    message: &'a str,
+}
+
+/// The threads turning output into JSON lines, see `log_structured()`.
+struct StructuredOutput {
+    /// The original stdout and stderr
+    saved: [c_int; 2],
+    /// Sent to by each thread once its pipe is closed and everything read from it is written
+    done: Receiver<()>,
+}
+
+impl StructuredOutput {
+    /// Put back the original stdout and stderr, closing our end of the pipes, and wait for the threads
+    /// to write what's left.
+    fn finish(self) {
+        unsafe {
+            dup2(self.saved[0], 1);
+            dup2(self.saved[1], 2);
+            close(self.saved[0]);
+            close(self.saved[1]);
+        }
+
+        let deadline = precise_time_ms() + FLUSH_TIMEOUT;
+        let mut finished = 0;
+
+        while finished < self.saved.len() && precise_time_ms() < deadline {
+            match self.done.try_recv() {
+                Ok(()) => finished += 1,
+                Err(TryRecvError::Empty) => sleep(Duration::milliseconds(10)),
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+/// Send the daemon's stdout and stderr, and those of hooks, to stdout as JSON lines
+/// until `finish()` is called on the result.
+fn log_structured() -> IoResult<StructuredOutput> {
+    let saved = [try!(cloexec(unsafe { dup(1) })), try!(cloexec(unsafe { dup(2) }))];
+    let out = try!(cloexec(unsafe { dup(1) }));
+    let (done, finished) = channel();
+
+    for &(fd, stream) in [(1, "stdout"), (2, "stderr")].iter() {
+        let mut fds = [0 as c_int; 2];
+
+        if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(IoError::last_error());
+        }
+
+        let read_fd = try!(cloexec(fds[0]));
+        let out = try!(cloexec(unsafe { dup(out) }));
+
+        if unsafe { dup2(fds[1], fd) } < 0 {
+            return Err(IoError::last_error());
+        }
+
+        unsafe { close(fds[1]); }
+
+        let done = done.clone();
+
+        Thread::spawn(move || {
+            let mut lines = BufferedReader::new(PipeStream::open(read_fd));
+            let mut out = PipeStream::open(out);
+
+            while let Ok(line) = lines.read_line() {
+                let entry = LogEntry {
+                    time: time::now_utc().rfc3339().to_string(),
+                    stream: stream,
+                    message: line.trim_right_matches('\n'),
+                };
+
+                // One write per line, so the two streams don't interleave within a line
+                if out.write_str(&*format!("{}\n", json::encode(&entry))).is_err() {
+                    break;
+                }
+            }
+
+            let _ = done.send(());
+        });
+    }
+
+    unsafe { close(out); }
+
+    Ok(StructuredOutput {
+        saved: saved,
+        done: finished,
+    })
+}
+
+fn cloexec(fd: c_int) -> IoResult<c_int> {
+    if fd < 0 || unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) } < 0 {
+        Err(IoError::last_error())
+    } else {
+        Ok(fd)
+    }
+}
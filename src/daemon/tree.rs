@@ -0,0 +1,157 @@
+//! The processes a server has started, found through `/proc`.
+//!
+//! Each server runs in its own session and process group, so signals can reach the whole group.
+//! Descendants that moved to another group are still found through their parent PIDs.
+
+use util::FormatBytes;
+
+use libc::{c_int, pid_t};
+
+use std::fmt;
+use std::io::{File, IoError, IoResult};
+use std::io::fs;
+use std::os;
+
+pub const SIGKILL: c_int = 9;
+pub const SIGTERM: c_int = 15;
+
+const ESRCH: i32 = 3;
+
+extern {
+    fn kill(pid: pid_t, sig: c_int) -> c_int;
+}
+
+#[derive(Clone)]
+pub struct ProcessEntry {
+    pub pid: i32,
+    pub ppid: i32,
+    pub pgid: i32,
+    pub state: String,
+    /// User and system time, in clock ticks
+    pub cpu_ticks: u64,
+    pub rss: u64,
+    pub command: String,
+    /// How far below the server's own process this one is
+    pub depth: usize,
+}
+
+impl ProcessEntry {
+    fn read(pid: i32) -> IoResult<ProcessEntry> {
+        let stat = try!(File::open(&Path::new(format!("/proc/{}/stat", pid))).read_to_string());
+
+        // The name in parentheses can contain spaces, so count columns from after it
+        let name_end = stat.rfind(')').unwrap_or(0);
+        let name = stat[..name_end].splitn(1, '(').nth(1).unwrap_or("").to_string();
+        let columns: Vec<&str> = stat[name_end + 1..].words().collect();
+
+        // Indexes are those from the man page for /proc/[pid]/stat, minus 3
+        let column = |index: usize| columns.get(index - 3).and_then(|col| col.parse::<u64>()).unwrap_or(0);
+
+        // Arguments are separated by NULs; kernel threads and zombies have none
+        let command = File::open(&Path::new(format!("/proc/{}/cmdline", pid))).read_to_string().ok()
+            .map(|cmdline| cmdline.split('\0').filter(|arg| !arg.is_empty()).collect::<Vec<_>>().connect(" "))
+            .and_then(|command| if command.is_empty() { None } else { Some(command) })
+            .unwrap_or_else(|| format!("[{}]", name));
+
+        Ok(ProcessEntry {
+            pid: pid,
+            ppid: column(4) as i32,
+            pgid: column(5) as i32,
+            state: columns.get(0).map_or("?".to_string(), |state| state.to_string()),
+            cpu_ticks: column(14) + column(15),
+            rss: column(24) * os::page_size() as u64,
+            command: command,
+            depth: 0,
+        })
+    }
+
+    pub fn header() -> &'static str {
+        "PID      PPID     STAT        RSS  COMMAND"
+    }
+}
+
+impl fmt::String for ProcessEntry {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.write_fmt(
+            format_args!(
+                "{:<8} {:<8} {:<4} {:>10}  {}{}",
+                self.pid,
+                self.ppid,
+                self.state,
+                FormatBytes(self.rss).to_string(),
+                "  ".repeat(self.depth),
+                self.command
+            )
+        )
+    }
+}
+
+/// A snapshot of `/proc`. The daemon takes one at most once per tick and shares it, rather than rescanning
+/// for every server.
+pub struct ProcessTable {
+    processes: Vec<ProcessEntry>,
+}
+
+impl ProcessTable {
+    pub fn scan() -> IoResult<ProcessTable> {
+        let mut processes = Vec::new();
+
+        for path in try!(fs::readdir(&Path::new("/proc"))).iter() {
+            if let Some(pid) = path.filename_str().and_then(|name| name.parse::<i32>()) {
+                // Processes can exit while we're looking
+                if let Ok(entry) = ProcessEntry::read(pid) {
+                    processes.push(entry);
+                }
+            }
+        }
+
+        Ok(ProcessTable { processes: processes })
+    }
+
+    /// The server's process followed by its descendants, depth first. Processes that were orphaned
+    /// but are still in the server's process group are listed after them.
+    pub fn tree(&self, root: i32) -> Vec<ProcessEntry> {
+        let mut tree: Vec<ProcessEntry> = Vec::new();
+        let mut stack = vec![(root, 0)];
+
+        while let Some((pid, depth)) = stack.pop() {
+            let entry = match self.processes.iter().find(|entry| entry.pid == pid) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let mut children: Vec<i32> = self.children_of(pid).iter().map(|child| child.pid).collect();
+            children.sort_by(|a, b| b.cmp(a));
+            stack.extend(children.into_iter().map(|child| (child, depth + 1)));
+
+            tree.push(ProcessEntry { depth: depth, ..entry.clone() });
+        }
+
+        let mut orphans: Vec<ProcessEntry> = self.processes.iter()
+            .filter(|entry| entry.pgid == root && !tree.iter().any(|seen| seen.pid == entry.pid))
+            .cloned()
+            .collect();
+        orphans.sort_by(|a, b| a.pid.cmp(&b.pid));
+        tree.extend(orphans.into_iter());
+
+        tree
+    }
+
+    /// The direct children of `ppid`, in no particular order.
+    pub fn children_of(&self, ppid: i32) -> Vec<&ProcessEntry> {
+        self.processes.iter().filter(|entry| entry.ppid == ppid).collect()
+    }
+}
+
+/// Send `signal` to every process in the group led by `pgid`.
+/// Returns `false` if the group has no processes left.
+pub fn signal_group(pgid: i32, signal: c_int) -> IoResult<bool> {
+    if unsafe { kill(-pgid as pid_t, signal) } == 0 {
+        return Ok(true);
+    }
+
+    match os::errno() {
+        errno if errno as i32 == ESRCH => Ok(false),
+        errno => Err(IoError::from_errno(errno, false)),
+    }
+}
@@ -1,17 +1,22 @@
 #![allow(unstable)]
 
 extern crate libc;
+extern crate regex;
 extern crate time;
 extern crate toml;
 extern crate "rustc-serialize" as rustc_serialize;
 
-use daemon::RemoteDaemon;
+use daemon::{RemoteDaemon, StdinLines};
+use daemon::protocol::OpError;
 
-use std::io::{IoResult, stdio};
+use std::borrow::ToOwned;
+use std::io::{IoError, IoResult, stdio};
 use std::os;
 
 mod config;
+mod cron;
 mod daemon;
+mod user;
 mod util;
 
 const MAX_TIMEOUTS: u32 = 20;
@@ -21,42 +26,188 @@ fn main() {
 
     let command = args.remove(0);
 
-    let config = config::Config::load().unwrap();
+    // Must happen before loading the config, the shim runs in the server's directory
+    if args.get(0).map_or(false, |op| &**op == daemon::shim::SHIM_OP) {
+        daemon::shim::run(&args[1..]);
+        return;
+    }
+
+    let explicit_config = config_arg(&mut args);
+    let config_path = match config::find_config(explicit_config.as_ref().map(|path| &**path)) {
+        Ok(path) => path,
+        Err(err) => return config_error(&err),
+    };
+
+    // Checked here rather than by the daemon, so a broken config can be fixed before starting one
+    if args.get(0).map_or(false, |op| &**op == "check-config") {
+        if !config::write_check(&config_path, &mut stdio::stdout()).unwrap() {
+            os::set_exit_status(1);
+        }
+        return;
+    }
+
+    let config = match config::Config::load(&config_path) {
+        Ok(config) => config,
+        Err(err) => return config_error(&err),
+    };
  
     if let Some(op) = args.get(0) {
         if &**op == "start-daemon" {
-            daemon::start(config);
+            // Claimed before forking, so a second daemon fails with its error on our stderr
+            let started = daemon::claim(&config).and_then(|pid_file| {
+                daemon::daemonize(&Path::new(&*config.daemon_log), &pid_file).map(|_| pid_file)
+            });
+
+            match started {
+                Ok(pid_file) => daemon::start(config, pid_file),
+                Err(err) => {
+                    let _ = writeln!(&mut stdio::stderr(), "Error starting daemon: {}", err);
+                    os::set_exit_status(1);
+                },
+            }
+
+            return;
+        }
+
+        if &**op == "run" {
+            if args.get(1).map_or(true, |arg| &**arg != "--foreground") {
+                let _ = writeln!(&mut stdio::stderr(), "Usage: run --foreground");
+                os::set_exit_status(1);
+                return;
+            }
+
+            daemon::run_foreground(config);
             return;
         }
     } 
     
-    let mut daemon = RemoteDaemon::connect_or_spawn(&*command, &*config.socket_path).unwrap();
+    let mut daemon = RemoteDaemon::connect_or_spawn(&*command, &config).unwrap();
 
     stdio::println("Connected to daemon.");
 
     if args.is_empty() {    
-        if command_loop(daemon).is_err() {
+        if command_loop(daemon, &StdinLines::new()).is_err() {
             stdio::println("\nConnection timed out..");
         } else {
             stdio::println("\nExiting...");
         }
     } else {
         let ref mut stdout = stdio::stdout();
-        daemon.send_command(&*args.connect(" ")).unwrap();
-        daemon.write_response(stdout, MAX_TIMEOUTS).unwrap();
+        let op = args.remove(0);
+
+        // Leave stdin alone unless the op reads it, it may be piped to something else
+        let outcome = if reads_stdin(&*op) {
+            daemon.follow(&*op, &*args, stdout, &StdinLines::new())
+        } else {
+            daemon.request(&*op, &*args, stdout, MAX_TIMEOUTS)
+        };
+
+        if let Err(err) = outcome.unwrap() {
+            print_error(&err);
+            os::set_exit_status(1);
+        }
     }
 }
 
-fn command_loop(mut daemon: RemoteDaemon) -> IoResult<()> {
+/// Take `--config <path>` or `--config=<path>` off the front of `args`.
+fn config_arg(args: &mut Vec<String>) -> Option<String> {
+    const FLAG: &'static str = "--config";
+
+    if args.len() > 1 && &*args[0] == FLAG {
+        args.remove(0);
+        return Some(args.remove(0));
+    }
+
+    if args.get(0).map_or(false, |arg| arg.starts_with("--config=")) {
+        return Some(args.remove(0)[FLAG.len() + 1..].to_owned());
+    }
+
+    None
+}
+
+fn command_loop(mut daemon: RemoteDaemon, input: &StdinLines) -> IoResult<()> {
     let mut stdout = stdio::stdout();
 
     try!(stdout.write_str("> ").and_then(|_| stdout.flush()));
-    for line in stdio::stdin().lock().lines().filter_map(|line| line.ok()) {
-        try!(daemon.send_command(&*line));
-        try!(daemon.write_response(&mut stdout, MAX_TIMEOUTS));
+
+    // Read through `input`, `follow` and `attach` need stdin too
+    while let Some(line) = input.read_line() {
+        let mut args: Vec<_> = line.words().map(ToOwned::to_owned).collect();
+
+        if !args.is_empty() {
+            let op = args.remove(0);
+
+            if let Err(err) = try!(run_op(&mut daemon, &*op, &*args, &mut stdout, input)) {
+                print_error(&err);
+            }
+        }
+
         try!(stdout.write_str("> ").and_then(|_| stdout.flush()));        
     }
     
     Ok(()) 
 }
 
+fn run_op<W: Writer>(daemon: &mut RemoteDaemon, op: &str, args: &[String], w: &mut W, input: &StdinLines) 
+-> IoResult<Result<(), OpError>> {
+    if reads_stdin(op) {
+        daemon.follow(op, args, w, input)
+    } else {
+        daemon.request(op, args, w, MAX_TIMEOUTS)
+    }
+}
+
+fn reads_stdin(op: &str) -> bool {
+    op == "follow" || op == "attach"
+}
+
+fn config_error(err: &IoError) {
+    let _ = writeln!(&mut stdio::stderr(), "Error loading config: {}", config::describe_error(err));
+    os::set_exit_status(1);
+}
+
+fn print_error(err: &OpError) {
+    let _ = writeln!(&mut stdio::stderr(), "Error ({:?}): {}", err.kind, err.message);
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::config_arg;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn separate_value() {
+        let mut rest = args(&["--config", "/etc/shepherd.toml", "start", "web"]);
+
+        assert_eq!(config_arg(&mut rest), Some("/etc/shepherd.toml".to_string()));
+        assert_eq!(rest, args(&["start", "web"]));
+    }
+
+    #[test]
+    fn joined_value() {
+        let mut rest = args(&["--config=shepherd.toml", "status"]);
+
+        assert_eq!(config_arg(&mut rest), Some("shepherd.toml".to_string()));
+        assert_eq!(rest, args(&["status"]));
+    }
+
+    #[test]
+    fn only_at_the_front() {
+        let mut rest = args(&["start", "--config", "shepherd.toml"]);
+
+        assert_eq!(config_arg(&mut rest), None);
+        assert_eq!(rest, args(&["start", "--config", "shepherd.toml"]));
+    }
+
+    #[test]
+    fn missing_value() {
+        let mut rest = args(&["--config"]);
+
+        assert_eq!(config_arg(&mut rest), None);
+        assert_eq!(rest, args(&["--config"]));
+    }
+}
@@ -0,0 +1,145 @@
+//! Resolving the user, groups and umask a server runs with.
+
+use config::ServerConfig;
+
+use libc::{c_char, gid_t, uid_t};
+
+use std::ffi::CString;
+use std::num;
+
+#[repr(C)]
+#[allow(dead_code)]
+struct passwd {
+    pw_name: *const c_char,
+    pw_passwd: *const c_char,
+    pw_uid: uid_t,
+    pw_gid: gid_t,
+    pw_gecos: *const c_char,
+    pw_dir: *const c_char,
+    pw_shell: *const c_char,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct group {
+    gr_name: *const c_char,
+    gr_passwd: *const c_char,
+    gr_gid: gid_t,
+    gr_mem: *const *const c_char,
+}
+
+extern {
+    fn getpwnam(name: *const c_char) -> *const passwd;
+    fn getpwuid(uid: uid_t) -> *const passwd;
+    fn getgrnam(name: *const c_char) -> *const group;
+}
+
+/// What to switch to before running a server. `None` fields are inherited from the daemon.
+#[derive(Clone, PartialEq, Show, RustcEncodable, RustcDecodable)]
+pub struct Credentials {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Supplementary groups
+    pub groups: Option<Vec<u32>>,
+    pub umask: Option<u32>,
+}
+
+impl Credentials {
+    /// Look up the configured user and groups. Names are resolved each time a server is spawned,
+    /// so changes to the system's users are picked up without a reload.
+    pub fn for_config(config: &ServerConfig) -> Result<Credentials, String> {
+        let (uid, user_gid) = match config.user {
+            Some(ref user) => {
+                let (uid, gid) = try!(lookup_user(&**user));
+                (Some(uid), gid)
+            },
+            None => (None, None),
+        };
+
+        let gid = match config.group {
+            Some(ref group) => Some(try!(lookup_group(&**group))),
+            None => user_gid,
+        };
+
+        if uid.is_some() && gid.is_none() {
+            return Err(format!(
+                "User \"{}\" has no passwd entry, so `group` must be set", config.user.as_ref().unwrap()
+            ));
+        }
+
+        let groups = match config.groups {
+            Some(ref names) => {
+                let mut groups = Vec::with_capacity(names.len());
+
+                for name in names.iter() {
+                    groups.push(try!(lookup_group(&**name)));
+                }
+
+                Some(groups)
+            },
+            // Don't let a server started as root keep root's groups
+            None => gid.map(|gid| vec![gid]),
+        };
+
+        let umask = match config.umask {
+            Some(ref umask) => Some(try!(parse_umask(&**umask))),
+            None => None,
+        };
+
+        Ok(Credentials {
+            uid: uid,
+            gid: gid,
+            groups: groups,
+            umask: umask,
+        })
+    }
+
+    /// Whether anything differs from the daemon's own credentials.
+    pub fn is_inherited(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none() && self.groups.is_none() && self.umask.is_none()
+    }
+}
+
+/// Find a user's ID and primary group by name or number.
+fn lookup_user(user: &str) -> Result<(u32, Option<u32>), String> {
+    let entry = match user.parse::<u32>() {
+        Some(uid) => unsafe { getpwuid(uid) },
+        None => {
+            let name = CString::from_slice(user.as_bytes());
+            unsafe { getpwnam(name.as_ptr()) }
+        },
+    };
+
+    if !entry.is_null() {
+        return Ok(unsafe { ((*entry).pw_uid, Some((*entry).pw_gid)) });
+    }
+
+    // A numeric user doesn't need to exist, but we won't know its group
+    match user.parse::<u32>() {
+        Some(uid) => Ok((uid, None)),
+        None => Err(format!("Unknown user \"{}\"", user)),
+    }
+}
+
+fn lookup_group(group: &str) -> Result<u32, String> {
+    if let Some(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+
+    let name = CString::from_slice(group.as_bytes());
+    let entry = unsafe { getgrnam(name.as_ptr()) };
+
+    if entry.is_null() {
+        Err(format!("Unknown group \"{}\"", group))
+    } else {
+        Ok(unsafe { (*entry).gr_gid })
+    }
+}
+
+/// Parse an octal umask like `"027"`.
+fn parse_umask(umask: &str) -> Result<u32, String> {
+    match num::from_str_radix::<u32>(umask, 8) {
+        Some(mask) if mask <= 0o777 => Ok(mask),
+        _ => Err(format!("Invalid umask \"{}\", expected octal like \"027\"", umask)),
+    }
+}
@@ -45,6 +45,14 @@ pub fn precise_time_ms() -> u64 {
     time::precise_time_ns() / 1_000_000    
 }
 
+/// The units of CPU time in `/proc`
+pub fn ticks_per_second() -> u64 {
+    use libc::sysconf;
+    use libc::consts::os::sysconf::_SC_CLK_TCK;
+
+    unsafe { sysconf(_SC_CLK_TCK) as u64 }
+}
+
 pub struct FormatTime(pub u64, pub u8, pub u8);
 
 impl FormatTime {
@@ -63,3 +71,14 @@ impl fmt::String for FormatTime {
         f.write_fmt(format_args!("{}:{:02}:{:02}", self.0, self.1, self.2))    
     }    
 }
+
+/// Whether `path` is a file the daemon may execute
+pub fn is_executable(path: &Path) -> bool {
+    use libc::funcs::posix88::unistd::access;
+    use libc::consts::os::posix88::X_OK;
+    use std::ffi::CString;
+    use std::io::fs::PathExtensions;
+
+    let path_c = CString::from_slice(path.as_vec());
+    path.is_file() && unsafe { access(path_c.as_ptr(), X_OK) } == 0
+}